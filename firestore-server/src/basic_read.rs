@@ -16,13 +16,22 @@ use crate::protos::document_protos::FieldValue;
 use crate::security_rules::{Operation, operation_is_allowed, UserId};
 use crate::security_rules::UserId::User;
 use crate::sql_types::field_value;
+use crate::subscriptions::{readable_documents, register_client_subscription};
+use crate::update_queue::{ResumePoint, Subscription, write_initial_updates};
+use crate::utils::apply_field_mask;
+
+pub const COLLECTION_DOCUMENTS_QUERY: &str =
+  "select document_data from documents where collection_parent_path = $1 and collection_id = $2";
+pub const COLLECTION_GROUP_DOCUMENTS_QUERY: &str =
+  "select document_data from documents where collection_id = $1";
 
 pub fn get_document(
   transaction: &mut Transaction,
   user_id: &UserId,
   collection_parent_path: &str,
   collection_id: &str,
-  document_id: &str)
+  document_id: &str,
+  field_mask: &Option<Vec<String>>)
   -> Option<Document>
 {
   // security check
@@ -46,14 +55,15 @@ pub fn get_document(
   let encoded_document: Vec<u8> = rows[0].get(0);
 
   let document: Document = Document::decode(&encoded_document[..]).unwrap();
-  Some(document)
+  Some(apply_field_mask(document, field_mask))
 }
 
 pub fn get_documents(
   transaction: &mut Transaction,
   user_id: &UserId,
   collection_parent_path: &str,
-  collection_id: &str)
+  collection_id: &str,
+  field_mask: &Option<Vec<String>>)
   -> Vec<Document>
 {
   // list security check
@@ -63,23 +73,16 @@ pub fn get_documents(
                                  collection_id, &None));
   }
 
-  let document_ids: Vec<String> = transaction.query(
-    "select document_id from documents where collection_parent_path = $1 and collection_id = $2",
-    &[&collection_parent_path, &collection_id]).unwrap().into_iter()
-    .map(|row| row.get(0))
-    .collect();
+  let rows = transaction.query(COLLECTION_DOCUMENTS_QUERY, &[&collection_parent_path, &collection_id]).unwrap();
 
-  let documents: Vec<Document> = document_ids.iter()
-    .map(|document_id| get_document(transaction, user_id, collection_parent_path, collection_id, document_id).unwrap())
-    .collect();
-
-  documents
+  decode_document_rows(&rows, field_mask)
 }
 
 pub fn get_documents_from_collection_group(
   transaction: &mut Transaction,
   user_id: &UserId,
-  collection_id: &str)
+  collection_id: &str,
+  field_mask: &Option<Vec<String>>)
   -> Vec<Document>
 {
   if let User(user_id) = user_id {
@@ -88,15 +91,21 @@ pub fn get_documents_from_collection_group(
                                  collection_id, &None));
   }
 
-  let document_id_rows: Vec<_> = transaction.query(
-    "select collection_parent_path, document_id from documents where collection_id = $1",
-    &[&collection_id],
-  ).unwrap();
+  let rows = transaction.query(COLLECTION_GROUP_DOCUMENTS_QUERY, &[&collection_id]).unwrap();
+
+  decode_document_rows(&rows, field_mask)
+}
 
-  let documents: Vec<Document> = document_id_rows.iter()
-    .map(|document_id_row| get_document(transaction, user_id, document_id_row.get(0), collection_id, document_id_row.get(1)).unwrap())
-    .collect();
-  documents
+// Decodes the document_data column of each row. Security checks must be performed by the
+// caller once per query rather than once per document.
+pub fn decode_document_rows(rows: &[Row], field_mask: &Option<Vec<String>>) -> Vec<Document> {
+  rows.iter()
+    .map(|row| {
+      let encoded_document: Vec<u8> = row.get("document_data");
+      let document: Document = Document::decode(&encoded_document[..]).unwrap();
+      apply_field_mask(document, field_mask)
+    })
+    .collect()
 }
 
 pub fn get_matching_basic_subscription_ids(
@@ -139,7 +148,8 @@ pub fn subscribe_to_document(
   collection_parent_path: &str,
   collection_id: &str,
   document_id: &str,
-) -> String
+  resume_point: &Option<ResumePoint>,
+) -> Subscription
 {
   if let User(user_id) = user_id {
     assert!(operation_is_allowed(user_id, &Operation::Get,
@@ -148,13 +158,16 @@ pub fn subscribe_to_document(
   }
 
   let subscription_id: String = Uuid::new_v4().as_simple().to_string();
-  transaction.execute("insert into client_subscriptions values ($1, $2)",
-                      &[&subscription_id, &client_id]).unwrap();
+  register_client_subscription(transaction, &subscription_id, client_id, user_id, &Some(collection_parent_path.to_owned()),
+                               collection_id, &Some(document_id.to_owned()), &None);
   transaction.execute("insert into basic_subscriptions values ($1, $2, $3, $4)",
                       &[&collection_parent_path, &collection_id, &document_id, &subscription_id]).unwrap();
 
-  // Todo: trigger first subscription update?
-  subscription_id
+  let snapshot: Vec<Document> = get_document(transaction, &UserId::Admin, collection_parent_path, collection_id, document_id, &None)
+    .into_iter().collect();
+  let snapshot = readable_documents(user_id, snapshot);
+  write_initial_updates(transaction, client_id, &subscription_id, resume_point, &Some(collection_parent_path.to_owned()),
+                        collection_id, &Some(document_id.to_owned()), &snapshot, false)
 }
 
 pub fn subscribe_to_collection(
@@ -162,8 +175,9 @@ pub fn subscribe_to_collection(
   client_id: &str,
   user_id: &UserId,
   collection_parent_path: &str,
-  collection_id: &str)
-  -> String
+  collection_id: &str,
+  resume_point: &Option<ResumePoint>)
+  -> Subscription
 {
   if let User(user_id) = user_id {
     assert!(operation_is_allowed(user_id, &Operation::List,
@@ -172,21 +186,24 @@ pub fn subscribe_to_collection(
   }
 
   let subscription_id: String = Uuid::new_v4().as_simple().to_string();
-  transaction.execute("insert into client_subscriptions values ($1, $2)",
-                      &[&subscription_id, &client_id]).unwrap();
+  register_client_subscription(transaction, &subscription_id, client_id, user_id, &Some(collection_parent_path.to_owned()),
+                               collection_id, &None, &None);
   transaction.execute("insert into basic_subscriptions values ($1, $2, NULL, $3)",
                       &[&collection_parent_path, &collection_id, &subscription_id]).unwrap();
 
-  // Todo: trigger first subscription update?
-  subscription_id
+  let snapshot = get_documents(transaction, &UserId::Admin, collection_parent_path, collection_id, &None);
+  let snapshot = readable_documents(user_id, snapshot);
+  write_initial_updates(transaction, client_id, &subscription_id, resume_point, &Some(collection_parent_path.to_owned()),
+                        collection_id, &None, &snapshot, false)
 }
 
 pub fn subscribe_to_collection_group(
   transaction: &mut Transaction,
   client_id: &str,
   user_id: &UserId,
-  collection_id: &str)
-  -> String
+  collection_id: &str,
+  resume_point: &Option<ResumePoint>)
+  -> Subscription
 {
   if let User(user_id) = user_id {
     assert!(operation_is_allowed(user_id, &Operation::List,
@@ -195,11 +212,11 @@ pub fn subscribe_to_collection_group(
   }
 
   let subscription_id: String = Uuid::new_v4().as_simple().to_string();
-  transaction.execute("insert into client_subscriptions values ($1, $2)",
-                      &[&subscription_id, &client_id]).unwrap();
+  register_client_subscription(transaction, &subscription_id, client_id, user_id, &None, collection_id, &None, &None);
   transaction.execute("insert into basic_subscriptions values (NULL, $1, NULL, $2)",
                       &[&collection_id, &subscription_id]).unwrap();
 
-  // Todo: trigger first subscription update?
-  subscription_id
+  let snapshot = get_documents_from_collection_group(transaction, &UserId::Admin, collection_id, &None);
+  let snapshot = readable_documents(user_id, snapshot);
+  write_initial_updates(transaction, client_id, &subscription_id, resume_point, &None, collection_id, &None, &snapshot, false)
 }
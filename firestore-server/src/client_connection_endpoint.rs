@@ -3,6 +3,9 @@ use std::time::{Duration, SystemTime};
 
 use postgres::Client;
 
+use crate::subscriptions::{acknowledge_subscription_cancellations, get_expired_subscriptions, get_subscription_cancellations};
+use crate::update_queue::ChangeType;
+
 const LONG_POLL_TIME_SECONDS: u64 = 20;
 
 pub fn listen_for_update(sql_client: &mut Client, user_client_id: &str) {
@@ -20,21 +23,27 @@ pub fn listen_for_update(sql_client: &mut Client, user_client_id: &str) {
   // Todo: close request with message that client is up to date
 }
 
+// Only the latest ping is kept, the subscription reaper expires subscriptions relative to it
 pub fn record_client_ping(sql_client: &mut Client, user_client_id: &str) {
   let now = SystemTime::now();
   sql_client.execute(
-    "insert into client_ping_times values ($1, $2)",
+    "insert into client_ping_times (client_id, ping_time) values ($1, $2)
+     ON CONFLICT (client_id) DO UPDATE SET ping_time = $2",
     &[&user_client_id, &now]).unwrap();
 }
 
+// A client is out of date when it has pending updates or cancelled subscriptions it hasn't been
+// told about
 pub fn client_is_out_of_date(sql_client: &mut Client, user_client_id: &str) -> bool {
   return sql_client.query(
     "SELECT 1 FROM client_subscriptions C JOIN update_queues U
      ON C.subscription_id = U.subscription_id 
      WHERE C.client_id = $1 
+     UNION ALL
+     SELECT 1 FROM subscription_cancellations WHERE client_id = $1
      LIMIT 1",
     &[&user_client_id])
-    .unwrap().len() == 0;
+    .unwrap().len() > 0;
 }
 
 pub struct UpdateValue {
@@ -44,14 +53,31 @@ pub struct UpdateValue {
   document_id: String,
   document_data: Option<Vec<u8>>,
   update_id: String,
+  resume_token: i64,
+  change_type: ChangeType,
+  old_index: Option<i32>,
+  new_index: Option<i32>,
+}
+
+pub struct ClientUpdates {
+  updates: Vec<UpdateValue>,
+  // The subscriptions to subscribe to again with their latest resume token
+  expired_subscription_ids: Vec<String>,
+  // Every cancelled subscription with the reason, the expired ones included
+  cancelled_subscriptions: Vec<(String, String)>,
 }
 
-pub fn get_updates(sql_client: &mut Client, user_client_id: &str) -> Vec<UpdateValue> {
-  sql_client.query(
-    "SELECT subscription_id, collection_parent_path, collection_id, document_id, document_data, update_id
+pub fn get_updates(sql_client: &mut Client, user_client_id: &str) -> ClientUpdates {
+  let mut transaction = sql_client.transaction().unwrap();
+  let expired_subscription_ids = get_expired_subscriptions(&mut transaction, user_client_id);
+  let cancelled_subscriptions = get_subscription_cancellations(&mut transaction, user_client_id);
+  let updates = transaction.query(
+    "SELECT U.subscription_id, collection_parent_path, collection_id, document_id, document_data, update_id,
+            resume_token, change_type, old_index, new_index
      FROM client_subscriptions C JOIN update_queues U 
      ON C.subscription_id = U.subscription_id 
      WHERE C.client_id = $1 
+     ORDER BY U.queue_position
      LIMIT 1",
     &[&user_client_id])
     .unwrap().into_iter()
@@ -62,8 +88,20 @@ pub fn get_updates(sql_client: &mut Client, user_client_id: &str) -> Vec<UpdateV
       document_id: row.get(3),
       document_data: row.get(4),
       update_id: row.get(5),
+      resume_token: row.get(6),
+      change_type: ChangeType::parse(row.get(7)),
+      old_index: row.get(8),
+      new_index: row.get(9),
     })
-    .collect()
+    .collect();
+  transaction.commit().unwrap();
+  ClientUpdates { updates, expired_subscription_ids, cancelled_subscriptions }
+}
+
+pub fn confirm_cancellations(sql_client: &mut Client, user_client_id: &str, subscription_ids: &[String]) {
+  let mut transaction = sql_client.transaction().unwrap();
+  acknowledge_subscription_cancellations(&mut transaction, user_client_id, subscription_ids);
+  transaction.commit().unwrap();
 }
 
 //Todo: needs verification and index
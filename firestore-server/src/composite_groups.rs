@@ -0,0 +1,444 @@
+use std::thread;
+use std::thread::{JoinHandle, sleep};
+use std::time::{Duration, Instant};
+
+use itertools::Itertools;
+use postgres::{Client, NoTls, Row, Transaction};
+use uuid::Uuid;
+
+use crate::basic_read::decode_document_rows;
+use crate::composite_query::{add_document_to_composite_query_table, CompositeFieldGroup, CompositeGroupState};
+use crate::subscriptions::cancel_subscription;
+use crate::utils::{MAX_IDENTIFIER_LENGTH, quote_identifier};
+
+const BACKFILL_BATCH_SIZE: i64 = 500;
+const OLDER_TRANSACTIONS_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const OLDER_TRANSACTIONS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+const OLDER_TRANSACTIONS_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DropSubscriptionPolicy {
+  Fail,
+  Cancel,
+}
+
+// Columns shared by the group tables. A field with one of these names would collide with them.
+const RESERVED_COLUMN_NAMES: [&str; 4] = ["collection_parent_path", "collection_id", "document_id", "subscription_id"];
+
+// Registers a composite field group and creates its lookup, included and excluded tables. A
+// collection parent path of None creates a collection group index. Fields are indexed in
+// ascending order unless they are listed as descending. The group starts out BUILDING, once the
+// transaction commits the backfill worker indexes the existing documents.
+pub fn create_composite_group(
+  transaction: &mut Transaction,
+  collection_parent_path: &Option<String>,
+  collection_id: &str,
+  primary_field_name: &str,
+  secondary_field_names: &[String],
+  descending_field_names: &[String],
+) -> CompositeFieldGroup {
+  register_composite_group(transaction, collection_parent_path, collection_id, primary_field_name,
+                           secondary_field_names, descending_field_names, &None)
+}
+
+// Changes the secondary fields or field directions of a group. The new version is built alongside
+// the old one, which keeps answering queries until the new version's backfill completes and
+// replaces it, taking over the subscriptions it can match.
+pub fn alter_composite_group(
+  transaction: &mut Transaction,
+  group_id: &str,
+  secondary_field_names: &[String],
+  descending_field_names: &[String],
+) -> CompositeFieldGroup {
+  // Locking the group's row makes concurrent alters of the same group wait for each other
+  transaction.execute("select group_id from composite_groups where group_id = $1 for update", &[&group_id]).unwrap();
+  let composite_group = get_composite_group(transaction, group_id)
+    .unwrap_or_else(|| panic!("No composite group with id {}", group_id));
+  let pending_replacement_ids: Vec<String> = transaction.query(
+    "select group_id from composite_groups where replaces_group_id = $1", &[&group_id],
+  ).unwrap().into_iter()
+    .map(|row| row.get(0))
+    .collect();
+  assert!(pending_replacement_ids.is_empty(),
+          "The composite group {} already has a pending replacement {:?}", group_id, pending_replacement_ids);
+  register_composite_group(transaction, &composite_group.collection_parent_path, &composite_group.collection_id,
+                           &composite_group.primary_field_name, secondary_field_names, descending_field_names,
+                           &Some(group_id.to_string()))
+}
+
+fn register_composite_group(
+  transaction: &mut Transaction,
+  collection_parent_path: &Option<String>,
+  collection_id: &str,
+  primary_field_name: &str,
+  secondary_field_names: &[String],
+  descending_field_names: &[String],
+  replaces_group_id: &Option<String>,
+) -> CompositeFieldGroup {
+  let mut sorted_secondary_field_names = secondary_field_names.to_vec();
+  sorted_secondary_field_names.sort();
+  sorted_secondary_field_names.dedup();
+  assert_eq!(sorted_secondary_field_names.len(), secondary_field_names.len(), "Duplicate secondary field names");
+  assert!(!sorted_secondary_field_names.iter().any(|x| x == primary_field_name),
+          "The primary field can't also be a secondary field");
+  let mut descending_field_names = descending_field_names.to_vec();
+  descending_field_names.sort();
+  descending_field_names.dedup();
+
+  let composite_group = CompositeFieldGroup {
+    group_id: Uuid::new_v4().as_simple().to_string(),
+    collection_parent_path: collection_parent_path.clone(),
+    collection_id: collection_id.to_string(),
+    primary_field_name: primary_field_name.to_string(),
+    sorted_secondary_field_names,
+    descending_field_names,
+    state: CompositeGroupState::Building,
+  };
+  validate_field_names(&composite_group);
+
+  // The backfill position starts before every document
+  transaction.execute(
+    "insert into composite_groups values ($1, $2, $3, $4, $5, $6, $7, '', '', $8)",
+    &[&composite_group.group_id, &composite_group.collection_parent_path, &composite_group.collection_id,
+      &composite_group.primary_field_name, &composite_group.sorted_secondary_field_names,
+      &composite_group.descending_field_names, &composite_group.state.as_str(), &replaces_group_id]).unwrap();
+  transaction.batch_execute(&composite_group_ddl(&composite_group)).unwrap();
+
+  composite_group
+}
+
+// Removes a group and its tables. The group's subscriptions can no longer be matched, so
+// depending on the policy the drop either fails or cancels them.
+pub fn drop_composite_group(
+  transaction: &mut Transaction,
+  group_id: &str,
+  subscription_policy: &DropSubscriptionPolicy,
+  reason: &str,
+) {
+  let composite_group = get_composite_group(transaction, group_id)
+    .unwrap_or_else(|| panic!("No composite group with id {}", group_id));
+
+  let subscription_ids: Vec<String> = transaction.query(
+    &format!("select subscription_id from {}", composite_group.included_subscription_table_name()), &[],
+  ).unwrap().into_iter()
+    .map(|row| row.get(0))
+    .collect();
+  if *subscription_policy == DropSubscriptionPolicy::Fail {
+    assert!(subscription_ids.is_empty(),
+            "The composite group {} has {} active subscriptions", group_id, subscription_ids.len());
+  }
+  for subscription_id in subscription_ids {
+    cancel_subscription(transaction, &subscription_id, reason);
+  }
+
+  transaction.batch_execute(&format!(
+    "DROP TABLE {}; DROP TABLE {}; DROP TABLE {};",
+    composite_group.lookup_table_name(),
+    composite_group.included_subscription_table_name(),
+    composite_group.excluded_subscription_table_name())).unwrap();
+  transaction.execute("delete from composite_groups where group_id = $1", &[&group_id]).unwrap();
+}
+
+pub fn get_composite_group(transaction: &mut Transaction, group_id: &str) -> Option<CompositeFieldGroup> {
+  transaction.query_opt("select * from composite_groups where group_id = $1", &[&group_id]).unwrap()
+    .map(|row| composite_group_from_row(&row))
+}
+
+pub fn get_composite_groups(transaction: &mut Transaction) -> Vec<CompositeFieldGroup> {
+  transaction.query("select * from composite_groups", &[]).unwrap().iter()
+    .map(composite_group_from_row)
+    .collect()
+}
+
+// Returns every group that indexes documents in the collection, including the collection group
+// groups for its collection id
+pub fn get_composite_groups_for_collection(
+  transaction: &mut Transaction,
+  collection_parent_path: &str,
+  collection_id: &str,
+) -> Vec<CompositeFieldGroup> {
+  transaction.query(
+    "select * from composite_groups
+     where collection_id = $1 and (collection_parent_path = $2 or collection_parent_path IS NULL)",
+    &[&collection_id, &collection_parent_path],
+  ).unwrap().iter()
+    .map(composite_group_from_row)
+    .collect()
+}
+
+// The groups with a new version that is still being built
+pub fn get_replaced_composite_group_ids(transaction: &mut Transaction) -> Vec<String> {
+  transaction.query("select replaces_group_id from composite_groups where replaces_group_id IS NOT NULL", &[]).unwrap()
+    .into_iter()
+    .map(|row| row.get(0))
+    .collect()
+}
+
+pub fn composite_group_is_ready(transaction: &mut Transaction, group_id: &str) -> bool {
+  transaction.query_opt("select state from composite_groups where group_id = $1", &[&group_id]).unwrap()
+    .map(|row| CompositeGroupState::parse(row.get(0)) == CompositeGroupState::Ready)
+    .unwrap_or(false)
+}
+
+// Runs the backfill on its own connection. Each batch is committed separately, so the backfill
+// doesn't hold locks on the whole collection and picks up where it left off if interrupted.
+pub fn spawn_composite_group_backfill(connection_string: String, group_id: String) -> JoinHandle<()> {
+  thread::spawn(move || {
+    let mut client = Client::connect(&connection_string, NoTls).unwrap();
+    if let Err(error) = run_composite_group_backfill(&mut client, &group_id) {
+      panic!("The backfill of composite group {} failed: {}", group_id, error);
+    }
+  })
+}
+
+// Finds the groups that are still BUILDING and backfills them, so a group is built however it was
+// created, and a backfill interrupted by a restart is picked up again
+pub fn spawn_composite_group_backfill_worker(connection_string: String, interval: Duration) -> JoinHandle<()> {
+  thread::spawn(move || {
+    let mut client = Client::connect(&connection_string, NoTls).unwrap();
+    loop {
+      let building_group_ids: Vec<String> = client.query(
+        "select group_id from composite_groups where state = $1", &[&CompositeGroupState::Building.as_str()],
+      ).unwrap().into_iter()
+        .map(|row| row.get(0))
+        .collect();
+      for group_id in building_group_ids {
+        // The group stays BUILDING, so the next pass tries it again
+        if let Err(error) = run_composite_group_backfill(&mut client, &group_id) {
+          eprintln!("The backfill of composite group {} failed: {}", group_id, error);
+        }
+      }
+      sleep(interval);
+    }
+  })
+}
+
+// Must only be called once the group's catalog row has committed
+pub fn run_composite_group_backfill(sql_client: &mut Client, group_id: &str) -> Result<(), String> {
+  wait_for_older_transactions(sql_client, group_id)?;
+  loop {
+    let mut transaction = sql_client.transaction().unwrap();
+    let is_done = backfill_composite_group_batch(&mut transaction, group_id, BACKFILL_BATCH_SIZE);
+    transaction.commit().unwrap();
+    if is_done {
+      return Ok(());
+    }
+  }
+}
+
+// A writer whose snapshot was taken before the group's catalog row committed doesn't index its
+// documents in the group, and the backfill can't see them until the writer commits. Like CREATE
+// INDEX CONCURRENTLY, the backfill waits for every client transaction on this database that
+// started before it to finish before its first scan, and gives up if they outlive the timeout.
+fn wait_for_older_transactions(sql_client: &mut Client, group_id: &str) -> Result<(), String> {
+  let started_at: String = sql_client.query_one("select clock_timestamp()::TEXT", &[]).unwrap().get(0);
+  let wait_start = Instant::now();
+  let mut last_logged_at = wait_start;
+  loop {
+    let older_transaction_pids: Vec<i32> = sql_client.query(
+      "select pid from pg_stat_activity
+       where backend_type = 'client backend' and datname = current_database()
+       and pid <> pg_backend_pid() and xact_start < $1::TEXT::TIMESTAMPTZ",
+      &[&started_at]).unwrap().into_iter()
+      .map(|row| row.get(0))
+      .collect();
+    if older_transaction_pids.is_empty() {
+      return Ok(());
+    }
+    if wait_start.elapsed() >= OLDER_TRANSACTIONS_TIMEOUT {
+      return Err(format!("The transactions of backends {:?} were still running after {:?}",
+                         older_transaction_pids, OLDER_TRANSACTIONS_TIMEOUT));
+    }
+    if last_logged_at.elapsed() >= OLDER_TRANSACTIONS_LOG_INTERVAL {
+      eprintln!("The backfill of composite group {} is waiting for the transactions of backends {:?}",
+                group_id, older_transaction_pids);
+      last_logged_at = Instant::now();
+    }
+    sleep(OLDER_TRANSACTIONS_POLL_INTERVAL);
+  }
+}
+
+// Indexes the next batch of documents after the group's backfill position and returns whether
+// the backfill is complete, in which case the group is marked READY
+pub fn backfill_composite_group_batch(transaction: &mut Transaction, group_id: &str, batch_size: i64) -> bool {
+  // Locking the catalog row keeps two backfills of the same group from interleaving
+  let row = transaction.query_one("select * from composite_groups where group_id = $1 FOR UPDATE", &[&group_id]).unwrap();
+  let composite_group = composite_group_from_row(&row);
+  if composite_group.state == CompositeGroupState::Ready {
+    return true;
+  }
+  let backfill_collection_parent_path: String = row.get("backfill_collection_parent_path");
+  let backfill_document_id: String = row.get("backfill_document_id");
+
+  // Locking the documents makes a concurrent write of one of them wait for the batch, so the
+  // lookup table never ends up with a stale version of a document
+  let rows = match &composite_group.collection_parent_path {
+    Some(collection_parent_path) => transaction.query(
+      "select collection_parent_path, document_id, document_data from documents
+       where collection_id = $1 and collection_parent_path = $2 and document_id > $3
+       order by document_id limit $4 FOR SHARE",
+      &[&composite_group.collection_id, &collection_parent_path, &backfill_document_id, &batch_size]).unwrap(),
+    None => transaction.query(
+      "select collection_parent_path, document_id, document_data from documents
+       where collection_id = $1 and (collection_parent_path, document_id) > ($2, $3)
+       order by collection_parent_path, document_id limit $4 FOR SHARE",
+      &[&composite_group.collection_id, &backfill_collection_parent_path, &backfill_document_id, &batch_size]).unwrap(),
+  };
+
+  let documents = decode_document_rows(&rows, &None);
+  for (row, document) in rows.iter().zip(documents.iter()) {
+    let collection_parent_path: String = row.get("collection_parent_path");
+    let document_id: String = row.get("document_id");
+    add_document_to_composite_query_table(transaction, &collection_parent_path, &composite_group.collection_id, &document_id, document, &composite_group);
+  }
+
+  if let Some(last_row) = rows.last() {
+    let collection_parent_path: String = last_row.get("collection_parent_path");
+    let document_id: String = last_row.get("document_id");
+    transaction.execute(
+      "update composite_groups set backfill_collection_parent_path = $2, backfill_document_id = $3 where group_id = $1",
+      &[&group_id, &collection_parent_path, &document_id]).unwrap();
+  }
+
+  let is_done = (rows.len() as i64) < batch_size;
+  if is_done {
+    transaction.execute(
+      "update composite_groups set state = $2 where group_id = $1",
+      &[&group_id, &CompositeGroupState::Ready.as_str()]).unwrap();
+    // An altered group takes over from its previous version in the same transaction, so there is
+    // always exactly one ready version
+    let replaces_group_id: Option<String> = row.get("replaces_group_id");
+    if let Some(replaces_group_id) = replaces_group_id {
+      if let Some(replaced_group) = get_composite_group(transaction, &replaces_group_id) {
+        move_composite_group_subscriptions(transaction, &replaced_group, &composite_group);
+        drop_composite_group(transaction, &replaces_group_id, &DropSubscriptionPolicy::Cancel,
+                             &format!("The composite group was replaced by {}, subscribe again", group_id));
+      }
+    }
+  }
+  is_done
+}
+
+// Subscriptions carry over to a group's new version when it matches them the same way, ie. it has
+// the same secondary fields and orders the primary field in the same direction, which keeps their
+// result positions valid. Subscriptions on secondary fields the new version doesn't have, or that
+// it would have to match on fields they don't filter, stay behind and are cancelled with the
+// previous version.
+fn move_composite_group_subscriptions(
+  transaction: &mut Transaction,
+  from_group: &CompositeFieldGroup,
+  to_group: &CompositeFieldGroup,
+) {
+  if from_group.sorted_secondary_field_names != to_group.sorted_secondary_field_names
+    || from_group.is_descending(&from_group.primary_field_name) != to_group.is_descending(&to_group.primary_field_name) {
+    return;
+  }
+
+  let mut included_columns = vec![from_group.min_column_name(), from_group.max_column_name()];
+  for field_name in &from_group.sorted_secondary_field_names {
+    included_columns.push(quote_identifier(field_name));
+    included_columns.push(CompositeFieldGroup::prefix_column_name(field_name));
+  }
+  included_columns.push("subscription_id".to_owned());
+  let included_columns = included_columns.join(", ");
+  transaction.batch_execute(&format!(
+    "insert into {0} ({2}) select {2} from {1};
+     delete from {1};
+     insert into {3} ({5}, subscription_id) select {5}, subscription_id from {4};
+     delete from {4};",
+    to_group.included_subscription_table_name(), from_group.included_subscription_table_name(), included_columns,
+    to_group.excluded_subscription_table_name(), from_group.excluded_subscription_table_name(),
+    from_group.excluded_column_name())).unwrap();
+  transaction.execute("update client_subscriptions set composite_group_id = $2 where composite_group_id = $1",
+                      &[&from_group.group_id, &to_group.group_id]).unwrap();
+}
+
+fn composite_group_from_row(row: &Row) -> CompositeFieldGroup {
+  CompositeFieldGroup {
+    group_id: row.get("group_id"),
+    collection_parent_path: row.get("collection_parent_path"),
+    collection_id: row.get("collection_id"),
+    primary_field_name: row.get("primary_field_name"),
+    sorted_secondary_field_names: row.get("sorted_secondary_field_names"),
+    descending_field_names: row.get("descending_field_names"),
+    state: CompositeGroupState::parse(row.get("state")),
+  }
+}
+
+fn validate_field_names(composite_group: &CompositeFieldGroup) {
+  let field_names = std::iter::once(&composite_group.primary_field_name)
+    .chain(composite_group.sorted_secondary_field_names.iter());
+  for field_name in field_names {
+    assert!(!RESERVED_COLUMN_NAMES.contains(&field_name.as_str()),
+            "{} can't be used in a composite group", field_name);
+    // The longest column derived from a field name is excluded_<primary field name>
+    assert!(field_name.len() + "excluded_".len() <= MAX_IDENTIFIER_LENGTH,
+            "The field name {} is too long to be used in a composite group", field_name);
+  }
+  for field_name in &composite_group.descending_field_names {
+    assert!(composite_group.has_field(field_name), "{} is not a field of the composite group", field_name);
+  }
+}
+
+fn composite_group_ddl(composite_group: &CompositeFieldGroup) -> String {
+  let primary_column = quote_identifier(&composite_group.primary_field_name);
+  let secondary_columns: Vec<String> = composite_group.sorted_secondary_field_names.iter()
+    .map(|x| quote_identifier(x))
+    .collect();
+  let prefix_columns: Vec<String> = composite_group.sorted_secondary_field_names.iter()
+    .map(|x| CompositeFieldGroup::prefix_column_name(x))
+    .collect();
+
+  let mut lookup_columns = vec![primary_column];
+  lookup_columns.extend(secondary_columns.iter().cloned());
+  // The equality filtered secondary fields come first in the index, so that the documents
+  // matching them can be read in the primary field's order, eg. created_at DESC within author
+  let mut index_field_names = composite_group.sorted_secondary_field_names.clone();
+  index_field_names.push(composite_group.primary_field_name.clone());
+  let index_columns: Vec<String> = index_field_names.iter()
+    .map(|x| format!("{} {}", quote_identifier(x), composite_group.field_direction(x)))
+    .collect();
+  // The column order matters, documents are inserted into the lookup table positionally
+  let lookup_table = format!(
+    "CREATE TABLE {0} (
+      collection_parent_path  TEXT,
+      collection_id           TEXT,
+      document_id             TEXT,
+      {1},
+      PRIMARY KEY (collection_parent_path, collection_id, document_id)
+    );
+    CREATE INDEX {2} ON {0}({3});",
+    composite_group.lookup_table_name(),
+    lookup_columns.iter().map(|x| format!("{} field_value", x)).join(",\n      "),
+    quote_identifier(&composite_group.lookup_index_name()),
+    index_columns.join(", "));
+
+  let mut included_columns = vec![composite_group.min_column_name(), composite_group.max_column_name()];
+  included_columns.extend(secondary_columns.iter().cloned());
+  let mut included_column_definitions = included_columns.clone();
+  included_column_definitions.extend(prefix_columns.into_iter());
+  let included_table = format!(
+    "CREATE TABLE {0} (
+      {1},
+      subscription_id  TEXT,
+      PRIMARY KEY (subscription_id)
+    );
+    CREATE INDEX {2} ON {0}({3});",
+    composite_group.included_subscription_table_name(),
+    included_column_definitions.iter().map(|x| format!("{} field_value", x)).join(",\n      "),
+    quote_identifier(&composite_group.included_subscription_index_name()),
+    included_columns.join(", "));
+
+  let excluded_table = format!(
+    "CREATE TABLE {0} (
+      {1} field_value,
+      subscription_id  TEXT,
+      PRIMARY KEY ({1}, subscription_id)
+    );
+    CREATE INDEX {2} ON {0}({1});",
+    composite_group.excluded_subscription_table_name(),
+    composite_group.excluded_column_name(),
+    quote_identifier(&composite_group.excluded_subscription_index_name()));
+
+  format!("{}\n{}\n{}", lookup_table, included_table, excluded_table)
+}
@@ -9,24 +9,12 @@ use postgres::{Client, NoTls, Row, Transaction};
 use postgres::types::{ToSql, Type};
 use prost::Message;
 use sql_query_builder;
-use uuid::Uuid;
-use crate::basic_read::get_document;
 
 use crate::protos::document_protos::Document;
 use crate::protos::document_protos::field_value::Value;
 use crate::protos::document_protos::FieldValue;
-use crate::security_rules::{Operation, operation_is_allowed, UserId};
-use crate::security_rules::UserId::User;
 use crate::sql_types::field_value;
-use crate::utils::{field_value_proto_to_sql, null_sql_field_value};
-
-#[derive(Debug, Clone)]
-pub struct QueryParameter {
-  pub field_name: String,
-  pub operator: String,
-  pub parameter: field_value,
-  pub is_primary: bool,
-}
+use crate::utils::{field_value_proto_to_sql, null_sql_field_value, quote_identifier, stored_prefix_constraint};
 
 #[derive(Debug, Clone)]
 pub struct CompositeFieldGroup {
@@ -35,53 +23,105 @@ pub struct CompositeFieldGroup {
   pub collection_id: String,
   pub primary_field_name: String,
   pub sorted_secondary_field_names: Vec<String>,
+  // Fields not listed here are ascending
+  pub descending_field_names: Vec<String>,
+  pub state: CompositeGroupState,
 }
 
 impl CompositeFieldGroup {
-  fn lookup_table_name(&self) -> String {
-    format!("composite_lookup_table_{}", self.group_id)
+  // A group without a collection parent path indexes every collection with its collection id
+  pub fn group_type(&self) -> CompositeFieldGroupType {
+    match self.collection_parent_path {
+      Some(_) => CompositeFieldGroupType::Collection,
+      None => CompositeFieldGroupType::CollectionGroup,
+    }
+  }
+
+  pub fn contains_document(&self, collection_parent_path: &str, collection_id: &str) -> bool {
+    if self.collection_id != collection_id {
+      return false;
+    }
+    match self.group_type() {
+      CompositeFieldGroupType::Collection => self.collection_parent_path.as_deref() == Some(collection_parent_path),
+      CompositeFieldGroupType::CollectionGroup => true,
+    }
   }
-  fn included_subscription_table_name(&self) -> String {
-    format!("composite_included_table_{}", self.group_id)
+
+  pub fn has_field(&self, field_name: &str) -> bool {
+    self.primary_field_name == field_name || self.sorted_secondary_field_names.iter().any(|x| x == field_name)
+  }
+
+  pub fn is_descending(&self, field_name: &str) -> bool {
+    self.descending_field_names.iter().any(|x| x == field_name)
+  }
+
+  pub fn field_direction(&self, field_name: &str) -> &'static str {
+    if self.is_descending(field_name) { "DESC" } else { "ASC" }
+  }
+
+  pub fn lookup_index_name(&self) -> String {
+    format!("composite_lookup_table_idx_{}", self.group_id)
   }
-  fn excluded_subscription_table_name(&self) -> String {
-    format!("composite_excluded_table_{}", self.group_id)
+  pub fn included_subscription_index_name(&self) -> String {
+    format!("composite_included_table_idx_{}", self.group_id)
+  }
+  pub fn excluded_subscription_index_name(&self) -> String {
+    format!("composite_excluded_table_idx_{}", self.group_id)
+  }
+
+  // Table and column names are returned quoted, ready to be spliced into sql
+  pub(crate) fn lookup_table_name(&self) -> String {
+    quote_identifier(&format!("composite_lookup_table_{}", self.group_id))
+  }
+  pub(crate) fn included_subscription_table_name(&self) -> String {
+    quote_identifier(&format!("composite_included_table_{}", self.group_id))
+  }
+  pub(crate) fn excluded_subscription_table_name(&self) -> String {
+    quote_identifier(&format!("composite_excluded_table_{}", self.group_id))
+  }
+  pub(crate) fn min_column_name(&self) -> String {
+    quote_identifier(&format!("min_{}", self.primary_field_name))
+  }
+  pub(crate) fn max_column_name(&self) -> String {
+    quote_identifier(&format!("max_{}", self.primary_field_name))
+  }
+  pub(crate) fn excluded_column_name(&self) -> String {
+    quote_identifier(&format!("excluded_{}", self.primary_field_name))
+  }
+  pub(crate) fn prefix_column_name(field_name: &str) -> String {
+    quote_identifier(&format!("prefix_{}", field_name))
   }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum CompositeFieldGroupType {
   Collection,
   CollectionGroup,
 }
 
-pub fn composite_query(transaction: &mut Transaction, user_id: &UserId, parameters: &[QueryParameter], composite_group: &CompositeFieldGroup) -> Vec<Document> {
-  if let User(user_id) = user_id {
-    assert!(operation_is_allowed(user_id, &Operation::List,
-                                 &composite_group.collection_parent_path,
-                                 &composite_group.collection_id, &None));
-  }
+// A group is BUILDING while its lookup table is backfilled from the existing documents. Writes
+// maintain the group in either state, but it can only answer queries once it is READY.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompositeGroupState {
+  Building,
+  Ready,
+}
 
-  let query_string = {
-    let mut query = sql_query_builder::Select::new()
-      .select("collection_parent_path, collection_id, document_id")
-      .from(&composite_group.lookup_table_name());
-    for (i, parameter) in parameters.iter().enumerate() {
-      let constraint = format!("{} {} ${}", parameter.field_name, parameter.operator, i + 1);
-      query = query.where_clause(&constraint);
+impl CompositeGroupState {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      CompositeGroupState::Building => "BUILDING",
+      CompositeGroupState::Ready => "READY",
     }
-    query.as_string()
-  };
-
-  let args: Vec<_> = parameters.iter().map(|p| &p.parameter as &(dyn ToSql + Sync)).collect();
+  }
 
-  let documents: Vec<Document> = transaction.query(&query_string, &args[..])
-    .unwrap()
-    .into_iter()
-    .map(|row| get_document(transaction, user_id,row.get("collection_parent_path"),
-                            row.get("collection_id"), row.get("document_id")).unwrap())
-    .collect();
-  documents
+  pub fn parse(state: &str) -> CompositeGroupState {
+    match state {
+      "BUILDING" => CompositeGroupState::Building,
+      "READY" => CompositeGroupState::Ready,
+      _ => panic!("Invalid composite group state {}", state),
+    }
+  }
 }
 
 pub fn add_document_to_composite_query_tables(
@@ -93,12 +133,13 @@ pub fn add_document_to_composite_query_tables(
   composite_groups: &[CompositeFieldGroup],
 )
 {
-  for composite_field_group in composite_groups {
+  for composite_field_group in composite_groups.iter()
+    .filter(|group| group.contains_document(collection_parent_path, collection_id)) {
     add_document_to_composite_query_table(transaction, collection_parent_path, collection_id, document_id, document, composite_field_group);
   }
 }
 
-fn add_document_to_composite_query_table(
+pub(crate) fn add_document_to_composite_query_table(
   transaction: &mut Transaction,
   collection_parent_path: &str,
   collection_id: &str,
@@ -106,18 +147,22 @@ fn add_document_to_composite_query_table(
   document: &Document,
   composite_field_group: &CompositeFieldGroup,
 ) {
+  // Collection group field groups span many collections, so not every document will have the primary field
+  if !document.fields.contains_key(&composite_field_group.primary_field_name) {
+    return;
+  }
   let (primary_value, secondary_values) = get_field_group_values(document, composite_field_group);
 
-  let table_name = format!("\"{}\"", composite_field_group.lookup_table_name());
   let query_string = {
     let mut query = sql_query_builder::Insert::new()
-      .insert_into(&table_name)
+      .insert_into(&composite_field_group.lookup_table_name())
       .values("($1, $2, $3, $4");
     for i in 0..secondary_values.len() {
       query = query.values(&format!("${}", i + 5));
     }
     query = query.raw_after(sql_query_builder::InsertClause::Values, ")");
-    query.as_string()
+    // The backfill and a concurrent write can both index the same document
+    format!("{} ON CONFLICT DO NOTHING", query.as_string())
   };
 
   let mut args: Vec<&(dyn ToSql + Sync)> = vec![&collection_parent_path, &collection_id, &document_id, &primary_value];
@@ -134,7 +179,8 @@ pub fn delete_document_from_composite_query_tables(
   composite_groups: &[CompositeFieldGroup],
 )
 {
-  for composite_field_group in composite_groups {
+  for composite_field_group in composite_groups.iter()
+    .filter(|group| group.contains_document(collection_parent_path, collection_id)) {
     delete_document_from_composite_query_table(transaction, collection_parent_path, collection_id, document_id, composite_field_group)
   }
 }
@@ -147,69 +193,172 @@ fn delete_document_from_composite_query_table(
   composite_field_group: &CompositeFieldGroup,
 ) {
   let query_string: String =
-    format!("delete from \"{}\" where collection_parent_path=$1 and collection_id=$2 and document_id=$3",
+    format!("delete from {} where collection_parent_path=$1 and collection_id=$2 and document_id=$3",
             composite_field_group.lookup_table_name());
   transaction.execute(&query_string, &[&collection_parent_path, &collection_id, &document_id]).unwrap();
 }
 
-pub fn get_matching_composite_query_subscriptions(
+fn get_matching_subscriptions_for_composite_group(
   transaction: &mut Transaction,
   document: &Document,
-  composite_groups: &[CompositeFieldGroup],
+  composite_group: &CompositeFieldGroup,
 ) -> Vec<String> {
-  let mut matching_subscriptions: Vec<String> = vec![];
-  for composite_group in composite_groups {
-    matching_subscriptions.extend(get_matching_subscriptions_for_composite_group(transaction, document, composite_group).into_iter());
+  let (query_string, args) = composite_subscription_match_statement(document, composite_group);
+  let args: Vec<&(dyn ToSql + Sync)> = args.iter().map(|x| x.as_ref()).collect();
+
+  let matching_subscription_ids = transaction.query(&query_string, &args).unwrap()
+    .into_iter()
+    .map(|x| x.get::<usize, String>(0))
+    .collect();
+
+  matching_subscription_ids
+}
+
+// Composite subscriptions are ordered, so the documents each subscription matches are kept to give
+// every change the document's position. Returns the position of the document in each subscription
+// it now matches.
+pub fn add_document_to_composite_subscription_results(
+  transaction: &mut Transaction,
+  document: &Document,
+  composite_groups: &[CompositeFieldGroup],
+) -> Vec<(String, i32)> {
+  let document_id = document.id.clone().unwrap();
+  let mut positions = vec![];
+  for composite_group in composite_groups.iter()
+    .filter(|group| group.contains_document(&document_id.collection_parent_path, &document_id.collection_id))
+    .filter(|group| document.fields.contains_key(&group.primary_field_name)) {
+    for subscription_id in get_matching_subscriptions_for_composite_group(transaction, document, composite_group) {
+      add_document_to_composite_subscription_result(transaction, &subscription_id, document, composite_group);
+      let position = composite_subscription_position(
+        transaction, &subscription_id, &document_id.collection_parent_path, &document_id.collection_id, &document_id.document_id);
+      positions.push((subscription_id, position));
+    }
   }
-  matching_subscriptions
+  positions
 }
 
-fn get_matching_subscriptions_for_composite_group(
+// Returns the position the document had in each subscription that matched it
+pub fn delete_document_from_composite_subscription_results(
+  transaction: &mut Transaction,
+  collection_parent_path: &str,
+  collection_id: &str,
+  document_id: &str,
+) -> Vec<(String, i32)> {
+  let subscription_ids: Vec<String> = transaction.query(
+    "select subscription_id from composite_subscription_results
+     where collection_parent_path = $1 and collection_id = $2 and document_id = $3",
+    &[&collection_parent_path, &collection_id, &document_id]).unwrap().into_iter()
+    .map(|row| row.get(0))
+    .collect();
+  let positions = subscription_ids.into_iter()
+    .map(|subscription_id| {
+      let position = composite_subscription_position(transaction, &subscription_id, collection_parent_path, collection_id, document_id);
+      (subscription_id, position)
+    })
+    .collect();
+  transaction.execute(
+    "delete from composite_subscription_results where collection_parent_path = $1 and collection_id = $2 and document_id = $3",
+    &[&collection_parent_path, &collection_id, &document_id]).unwrap();
+  positions
+}
+
+pub(crate) fn remove_document_from_composite_subscription_result(
   transaction: &mut Transaction,
+  subscription_id: &str,
+  collection_parent_path: &str,
+  collection_id: &str,
+  document_id: &str,
+) {
+  transaction.execute(
+    "delete from composite_subscription_results
+     where subscription_id = $1 and collection_parent_path = $2 and collection_id = $3 and document_id = $4",
+    &[&subscription_id, &collection_parent_path, &collection_id, &document_id]).unwrap();
+}
+
+pub(crate) fn add_document_to_composite_subscription_result(
+  transaction: &mut Transaction,
+  subscription_id: &str,
   document: &Document,
   composite_group: &CompositeFieldGroup,
-) -> Vec<String> {
-  let primary_field_name = &composite_group.primary_field_name;
+) {
+  let document_id = document.id.clone().unwrap();
+  let (primary_value, _) = get_field_group_values(document, composite_group);
+  let descending = composite_group.is_descending(&composite_group.primary_field_name);
+  transaction.execute(
+    "insert into composite_subscription_results values ($1, $2, $3, $4, $5, $6)",
+    &[&subscription_id, &document_id.collection_parent_path, &document_id.collection_id, &document_id.document_id,
+      &primary_value, &descending]).unwrap();
+}
+
+// The number of documents ahead of the document in the subscription's order, see
+// composite_query_statement
+fn composite_subscription_position(
+  transaction: &mut Transaction,
+  subscription_id: &str,
+  collection_parent_path: &str,
+  collection_id: &str,
+  document_id: &str,
+) -> i32 {
+  let row = transaction.query_one(
+    "select primary_value, descending from composite_subscription_results
+     where subscription_id = $1 and collection_parent_path = $2 and collection_id = $3 and document_id = $4",
+    &[&subscription_id, &collection_parent_path, &collection_id, &document_id]).unwrap();
+  let primary_value: field_value = row.get(0);
+  let descending: bool = row.get(1);
+  // A known cost: the count reads the order index entries of every document ahead, so changes
+  // near the end of a subscription with many results take longer
+  let comparison = if descending { ">" } else { "<" };
+  transaction.query_one(
+    &format!(
+      "select count(*)::INTEGER from composite_subscription_results
+       where subscription_id = $1 and (primary_value, collection_parent_path, collection_id, document_id) {} ($2, $3, $4, $5)",
+      comparison),
+    &[&subscription_id, &primary_value, &collection_parent_path, &collection_id, &document_id]).unwrap().get(0)
+}
+
+pub fn composite_subscription_match_statement(
+  document: &Document,
+  composite_group: &CompositeFieldGroup,
+) -> (String, Vec<Box<dyn ToSql + Sync>>) {
+  let (primary_value, secondary_values) = get_field_group_values(document, composite_group);
+  let mut args: Vec<Box<dyn ToSql + Sync>> = vec![Box::new(primary_value)];
+
   let included_query_string = {
     let mut included_query = sql_query_builder::Select::new()
       .select("subscription_id")
-      .from(&format!("{}", composite_group.included_subscription_table_name()));
-
-    if document.fields.contains_key(primary_field_name) {
-      included_query = included_query
-        .where_clause(&format!("min_{0} <= $1", primary_field_name))
-        .where_clause(&format!("max_{0} >= $1", primary_field_name));
-    }
+      .from(&composite_group.included_subscription_table_name())
+      .where_clause(&format!("{} <= $1", composite_group.min_column_name()))
+      .where_clause(&format!("{} >= $1", composite_group.max_column_name()));
 
-    for (i, field_name) in composite_group.sorted_secondary_field_names.iter().enumerate() {
+    for (field_name, secondary_value) in composite_group.sorted_secondary_field_names.iter().zip(secondary_values.into_iter()) {
       if document.fields.contains_key(field_name) {
-        included_query = included_query.where_clause(&format!("{0} = ${1}", field_name, i + 2));
+        let value_arg = args.len() + 1;
+        let prefix_column = CompositeFieldGroup::prefix_column_name(field_name);
+        match stored_prefix_constraint(&prefix_column, value_arg + 1, &secondary_value) {
+          None => {
+            included_query = included_query.where_clause(&format!("{0} = ${1}", quote_identifier(field_name), value_arg));
+            args.push(Box::new(secondary_value));
+          }
+          Some((prefix_constraint, prefix_arg)) => {
+            included_query = included_query.where_clause(
+              &format!("({0} = ${1} or {2})", quote_identifier(field_name), value_arg, prefix_constraint));
+            args.push(Box::new(secondary_value));
+            args.push(prefix_arg);
+          }
+        }
       }
     }
     included_query.as_string()
   };
 
   let excluded_query_string =
-    format!("select distinct subscription_id from {} where excluded_{} = $1",
-            composite_group.excluded_subscription_table_name(), primary_field_name);
-
-
-  let query_string = format!("({}) EXCEPT ({})",
-                             included_query_string, excluded_query_string);
+    format!("select distinct subscription_id from {} where {} = $1",
+            composite_group.excluded_subscription_table_name(), composite_group.excluded_column_name());
 
-  let (primary_value, secondary_values) = get_field_group_values(document, composite_group);
-  let mut args: Vec<&(dyn ToSql + Sync)> = vec![&primary_value];
-  args.extend(secondary_values.iter().map(|x| x as &(dyn ToSql + Sync)));
-
-  let matching_subscription_ids = transaction.query(&query_string, &args).unwrap()
-    .into_iter()
-    .map(|x| x.get::<usize, String>(0))
-    .collect();
-
-  matching_subscription_ids
+  (format!("({}) EXCEPT ({})", included_query_string, excluded_query_string), args)
 }
 
-fn get_field_group_values(
+pub fn get_field_group_values(
   document: &Document,
   composite_field_group: &CompositeFieldGroup,
 ) -> (field_value, Vec<field_value>) {
@@ -225,74 +374,3 @@ fn get_field_group_values(
   (primary_value, secondary_values)
 }
 
-pub fn subscribe_to_composite_query(
-  transaction: &mut Transaction,
-  client_id: &str,
-  user_id: &UserId,
-  sorted_parameters: &[QueryParameter],
-  composite_group: &CompositeFieldGroup)
-  -> String
-{
-  if let User(user_id) = user_id {
-    assert!(operation_is_allowed(user_id, &Operation::List,
-                                 &composite_group.collection_parent_path,
-                                 &composite_group.collection_id, &None));
-  }
-
-  let subscription_id: String = Uuid::new_v4().as_simple().to_string();
-  transaction.execute("insert into client_subscriptions values ($1, $2)",
-                      &[&subscription_id, &client_id]).unwrap();
-
-  let mut primary_less_than_param = field_value::min();
-  let mut primary_greater_than_parameter = field_value::max();
-  let mut primary_excluded_parameters = vec![];
-  let mut secondary_parameters = vec![];
-
-  for parameter in sorted_parameters {
-    if parameter.is_primary {
-      match parameter.operator.as_str() {
-        "<=" => primary_less_than_param = parameter.parameter.clone(),
-        ">=" => primary_greater_than_parameter = parameter.parameter.clone(),
-        "<" => {
-          primary_less_than_param = parameter.parameter.clone();
-          primary_excluded_parameters.push(parameter.parameter.clone());
-        }
-        ">" => {
-          primary_greater_than_parameter = parameter.parameter.clone();
-          primary_excluded_parameters.push(parameter.parameter.clone());
-        }
-        "=" => {
-          primary_less_than_param = parameter.parameter.clone();
-          primary_greater_than_parameter = parameter.parameter.clone();
-        }
-        "!=" => primary_excluded_parameters.push(parameter.parameter.clone()),
-        _ => panic!("Invalid query argument provided")
-      }
-    } else {
-      secondary_parameters.push(parameter.parameter.clone());
-    }
-  }
-
-  let mut row_string = "($1, $2, $3".to_owned();
-  for i in 0..secondary_parameters.len() {
-    row_string.push_str(&format!(", ${}", i + 4));
-  }
-  row_string.push(')');
-  let included_query_string = format!("insert into {} values {}", composite_group.included_subscription_table_name(), row_string);
-  let mut included_args: Vec<&(dyn ToSql + Sync)> = vec![&subscription_id, &primary_greater_than_parameter, &primary_less_than_param];
-  for secondary_parameter in secondary_parameters.iter() {
-    included_args.push(secondary_parameter);
-  }
-
-  transaction.execute(&included_query_string,
-                      &included_args).unwrap();
-  let excluded_query_string = format!("insert into {} values ($1, $2)", composite_group.excluded_subscription_table_name());
-  for excluded_value in primary_excluded_parameters {
-    transaction.execute(&excluded_query_string, &[&subscription_id, &excluded_value]).unwrap();
-  }
-
-  // Todo: trigger first subscription update?
-
-  subscription_id
-}
-
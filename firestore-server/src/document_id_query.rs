@@ -0,0 +1,96 @@
+use postgres::types::ToSql;
+
+// The document id fields that can be filtered and ordered on alongside a simple or composite
+// query, equivalent to Firestore's __name__. The full path of a document is its collection
+// parent path, collection id and document id, eg. /users/AAA/posts/111
+#[derive(Debug, Clone, PartialEq)]
+pub enum DocumentIdField {
+  DocumentId,
+  FullPath,
+}
+
+// Operators are <, <=, =, !=, >, >= with a single value, or in with any number of values
+#[derive(Debug, Clone)]
+pub struct DocumentIdFilter {
+  pub field: DocumentIdField,
+  pub operator: String,
+  pub values: Vec<String>,
+}
+
+// Full paths are compared as (collection_parent_path, collection_id, document_id) tuples, the same
+// way document_id_order_clause orders them. Comparing the concatenated path would order
+// differently within a collection group, eg. /u/zz/1 and /u/a/b/c/1, and break paging.
+fn document_id_columns(table_alias: &str, field: &DocumentIdField) -> String {
+  match field {
+    DocumentIdField::DocumentId => format!("{}.document_id", table_alias),
+    DocumentIdField::FullPath =>
+      format!("({0}.collection_parent_path, {0}.collection_id, {0}.document_id)", table_alias),
+  }
+}
+
+// Splits a full path into its collection parent path, collection id and document id, eg.
+// /users/AAA/posts/111 into /users/AAA/, posts and 111
+fn split_full_path(full_path: &str) -> (String, String, String) {
+  let (collection_path, document_id) = full_path.rsplit_once('/')
+    .unwrap_or_else(|| panic!("Invalid document path {}", full_path));
+  let (collection_parent_path, collection_id) = collection_path.rsplit_once('/')
+    .unwrap_or_else(|| panic!("Invalid document path {}", full_path));
+  assert!(!collection_id.is_empty() && !document_id.is_empty(), "Invalid document path {}", full_path);
+  (format!("{}/", collection_parent_path), collection_id.to_owned(), document_id.to_owned())
+}
+
+// Returns the where constraints and their arguments for the document id filters, numbering the
+// arguments from arg_count
+pub fn document_id_constraints(
+  table_alias: &str,
+  filters: &[DocumentIdFilter],
+  arg_count: usize,
+) -> (Vec<String>, Vec<Box<dyn ToSql + Sync>>) {
+  let mut constraints = vec![];
+  let mut args: Vec<Box<dyn ToSql + Sync>> = vec![];
+  for filter in filters {
+    let columns = document_id_columns(table_alias, &filter.field);
+    let arg = arg_count + args.len();
+    match (filter.operator.as_str(), &filter.field) {
+      ("<" | "<=" | "=" | "!=" | ">" | ">=", DocumentIdField::DocumentId) => {
+        assert_eq!(filter.values.len(), 1, "The {} operator requires exactly one document id", filter.operator);
+        constraints.push(format!("{} {} ${}", columns, filter.operator, arg));
+        args.push(Box::new(filter.values[0].clone()));
+      }
+      ("<" | "<=" | "=" | "!=" | ">" | ">=", DocumentIdField::FullPath) => {
+        assert_eq!(filter.values.len(), 1, "The {} operator requires exactly one document path", filter.operator);
+        let (collection_parent_path, collection_id, document_id) = split_full_path(&filter.values[0]);
+        constraints.push(format!("{} {} (${}, ${}, ${})", columns, filter.operator, arg, arg + 1, arg + 2));
+        args.push(Box::new(collection_parent_path));
+        args.push(Box::new(collection_id));
+        args.push(Box::new(document_id));
+      }
+      ("in", DocumentIdField::DocumentId) => {
+        constraints.push(format!("{} = ANY(${})", columns, arg));
+        args.push(Box::new(filter.values.clone()));
+      }
+      ("in", DocumentIdField::FullPath) => {
+        let paths: Vec<(String, String, String)> = filter.values.iter().map(|x| split_full_path(x)).collect();
+        constraints.push(format!("{} IN (select * from unnest(${}::TEXT[], ${}::TEXT[], ${}::TEXT[]))",
+                                 columns, arg, arg + 1, arg + 2));
+        args.push(Box::new(paths.iter().map(|x| x.0.clone()).collect::<Vec<String>>()));
+        args.push(Box::new(paths.iter().map(|x| x.1.clone()).collect::<Vec<String>>()));
+        args.push(Box::new(paths.into_iter().map(|x| x.2).collect::<Vec<String>>()));
+      }
+      _ => panic!("Invalid document id operator provided")
+    }
+  }
+  (constraints, args)
+}
+
+// Ordering by full path orders by the path components, which keeps the ordering stable for
+// pagination and lets postgres use the documents primary key
+pub fn document_id_order_clause(table_alias: &str, field: &DocumentIdField, descending: bool) -> String {
+  let direction = if descending { "DESC" } else { "ASC" };
+  match field {
+    DocumentIdField::DocumentId =>
+      format!("{0}.document_id {1}, {0}.collection_parent_path {1}, {0}.collection_id {1}", table_alias, direction),
+    DocumentIdField::FullPath =>
+      format!("{0}.collection_parent_path {1}, {0}.collection_id {1}, {0}.document_id {1}", table_alias, direction),
+  }
+}
@@ -0,0 +1,93 @@
+use postgres::{Portal, Transaction};
+use postgres::types::ToSql;
+
+use crate::basic_read::{COLLECTION_DOCUMENTS_QUERY, COLLECTION_GROUP_DOCUMENTS_QUERY, decode_document_rows};
+use crate::protos::document_protos::Document;
+use crate::security_rules::{Operation, operation_is_allowed, UserId};
+use crate::security_rules::UserId::User;
+
+// Streaming variants of the read functions. Each stream is backed by a Postgres portal, so only
+// one batch of documents is held in memory at a time. The portal lives as long as the transaction
+// it was bound in, so the stream borrows the transaction until it is dropped.
+pub struct DocumentStream<'a, 'b> {
+  transaction: &'a mut Transaction<'b>,
+  portal: Portal,
+  batch_size: i32,
+  field_mask: Option<Vec<String>>,
+  exhausted: bool,
+}
+
+impl<'a, 'b> Iterator for DocumentStream<'a, 'b> {
+  type Item = Vec<Document>;
+
+  fn next(&mut self) -> Option<Vec<Document>> {
+    if self.exhausted {
+      return None;
+    }
+
+    let rows = self.transaction.query_portal(&self.portal, self.batch_size).unwrap();
+    if rows.len() < self.batch_size as usize {
+      self.exhausted = true;
+    }
+    if rows.is_empty() {
+      return None;
+    }
+    Some(decode_document_rows(&rows, &self.field_mask))
+  }
+}
+
+pub(crate) fn bind_document_stream<'a, 'b>(
+  transaction: &'a mut Transaction<'b>,
+  query_string: &str,
+  args: &[&(dyn ToSql + Sync)],
+  batch_size: i32,
+  field_mask: &Option<Vec<String>>,
+) -> DocumentStream<'a, 'b> {
+  assert!(batch_size > 0, "batch_size must be positive");
+  let portal = transaction.bind(query_string, args).unwrap();
+  DocumentStream {
+    transaction,
+    portal,
+    batch_size,
+    field_mask: field_mask.clone(),
+    exhausted: false,
+  }
+}
+
+pub fn stream_documents<'a, 'b>(
+  transaction: &'a mut Transaction<'b>,
+  user_id: &UserId,
+  collection_parent_path: &str,
+  collection_id: &str,
+  field_mask: &Option<Vec<String>>,
+  batch_size: i32)
+  -> DocumentStream<'a, 'b>
+{
+  if let User(user_id) = user_id {
+    assert!(operation_is_allowed(user_id, &Operation::List,
+                                 &Some(collection_parent_path.to_owned()),
+                                 collection_id, &None));
+  }
+
+  bind_document_stream(transaction, COLLECTION_DOCUMENTS_QUERY,
+                       &[&collection_parent_path, &collection_id], batch_size, field_mask)
+}
+
+pub fn stream_documents_from_collection_group<'a, 'b>(
+  transaction: &'a mut Transaction<'b>,
+  user_id: &UserId,
+  collection_id: &str,
+  field_mask: &Option<Vec<String>>,
+  batch_size: i32)
+  -> DocumentStream<'a, 'b>
+{
+  if let User(user_id) = user_id {
+    assert!(operation_is_allowed(user_id, &Operation::List,
+                                 &None,
+                                 collection_id, &None));
+  }
+
+  bind_document_stream(transaction, COLLECTION_GROUP_DOCUMENTS_QUERY,
+                       &[&collection_id], batch_size, field_mask)
+}
+
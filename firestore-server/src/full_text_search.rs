@@ -0,0 +1,155 @@
+use postgres::Transaction;
+
+use crate::basic_read::decode_document_rows;
+use crate::protos::document_protos::Document;
+use crate::protos::document_protos::field_value::Value;
+use crate::security_rules::{Operation, operation_is_allowed, UserId};
+use crate::security_rules::UserId::User;
+
+const TEXT_SEARCH_CONFIG: &str = "english";
+const BACKFILL_BATCH_SIZE: i64 = 500;
+
+// Opts a string field into full text indexing and indexes the field in the existing documents. A
+// collection parent path of None enables the field for every collection with the collection id.
+pub fn enable_full_text_search(
+  transaction: &mut Transaction,
+  collection_parent_path: &Option<String>,
+  collection_id: &str,
+  field_name: &str,
+) {
+  transaction.execute(
+    "insert into full_text_search_fields values ($1, $2, $3) ON CONFLICT DO NOTHING",
+    &[&collection_parent_path, &collection_id, &field_name]).unwrap();
+
+  // Documents are stored encoded, so they are decoded in batches. A document may already be
+  // indexed through a broader or narrower scope of the same field.
+  let query_string = format!(
+    "insert into full_text_search_lookup values ($1, $2, $3, $4, to_tsvector('{}', $5)) ON CONFLICT DO NOTHING",
+    TEXT_SEARCH_CONFIG);
+  let mut last_collection_parent_path = String::new();
+  let mut last_document_id = String::new();
+  loop {
+    let rows = transaction.query(
+      "select collection_parent_path, document_id, document_data from documents
+       where ($1::TEXT IS NULL or collection_parent_path = $1) and collection_id = $2
+         and (collection_parent_path, document_id) > ($3, $4)
+       order by collection_parent_path, document_id limit $5",
+      &[&collection_parent_path, &collection_id, &last_collection_parent_path, &last_document_id, &BACKFILL_BATCH_SIZE]).unwrap();
+    let documents = decode_document_rows(&rows, &None);
+    for (row, document) in rows.iter().zip(documents.iter()) {
+      if let Some(Value::StringValue(text)) = document.fields.get(field_name).and_then(|x| x.value.as_ref()) {
+        let document_collection_parent_path: String = row.get("collection_parent_path");
+        let document_id: String = row.get("document_id");
+        transaction.execute(
+          &query_string,
+          &[&document_collection_parent_path, &collection_id, &document_id, &field_name, &text]).unwrap();
+      }
+    }
+    match rows.last() {
+      Some(last_row) if (rows.len() as i64) == BACKFILL_BATCH_SIZE => {
+        last_collection_parent_path = last_row.get("collection_parent_path");
+        last_document_id = last_row.get("document_id");
+      }
+      _ => return,
+    }
+  }
+}
+
+pub fn disable_full_text_search(
+  transaction: &mut Transaction,
+  collection_parent_path: &Option<String>,
+  collection_id: &str,
+  field_name: &str,
+) {
+  transaction.execute(
+    "delete from full_text_search_fields where collection_parent_path IS NOT DISTINCT FROM $1 and collection_id = $2 and field_name = $3",
+    &[&collection_parent_path, &collection_id, &field_name]).unwrap();
+  // The field stays indexed in the collections another scope still enables it for
+  transaction.execute(
+    "delete from full_text_search_lookup L
+     where ($1::TEXT IS NULL or L.collection_parent_path = $1) and L.collection_id = $2 and L.field_name = $3
+       and NOT EXISTS (
+         select 1 from full_text_search_fields F
+         where F.collection_id = L.collection_id and F.field_name = L.field_name
+           and (F.collection_parent_path IS NULL or F.collection_parent_path = L.collection_parent_path))",
+    &[&collection_parent_path, &collection_id, &field_name]).unwrap();
+}
+
+fn get_full_text_search_fields(
+  transaction: &mut Transaction,
+  collection_parent_path: &str,
+  collection_id: &str,
+) -> Vec<String> {
+  transaction.query(
+    "select distinct field_name from full_text_search_fields
+     where (collection_parent_path = $1 or collection_parent_path IS NULL) and collection_id = $2",
+    &[&collection_parent_path, &collection_id],
+  ).unwrap().into_iter()
+    .map(|row| row.get(0))
+    .collect()
+}
+
+pub fn add_document_to_full_text_search_table(
+  transaction: &mut Transaction,
+  collection_parent_path: &str,
+  collection_id: &str,
+  document_id: &str,
+  document: &Document,
+) {
+  for field_name in get_full_text_search_fields(transaction, collection_parent_path, collection_id) {
+    if let Some(Value::StringValue(text)) = document.fields.get(&field_name).and_then(|x| x.value.as_ref()) {
+      let query_string = format!("insert into full_text_search_lookup values ($1, $2, $3, $4, to_tsvector('{}', $5))", TEXT_SEARCH_CONFIG);
+      transaction.execute(
+        &query_string,
+        &[&collection_parent_path, &collection_id, &document_id, &field_name, &text]).unwrap();
+    }
+  }
+}
+
+pub fn delete_document_from_full_text_search_table(
+  transaction: &mut Transaction,
+  collection_parent_path: &str,
+  collection_id: &str,
+  document_id: &str,
+) {
+  transaction.execute(
+    "delete from full_text_search_lookup where collection_parent_path=$1 and collection_id=$2 and document_id=$3",
+    &[&collection_parent_path, &collection_id, &document_id]).unwrap();
+}
+
+// Returns the documents matching the search text ordered from most to least relevant. The search
+// text uses web search syntax, eg. `refund -shipping "credit card"`. A collection parent path of
+// None searches the whole collection group.
+pub fn search(
+  transaction: &mut Transaction,
+  user_id: &UserId,
+  collection_parent_path: &Option<String>,
+  collection_id: &str,
+  search_text: &str,
+  limit: i64,
+  field_mask: &Option<Vec<String>>,
+) -> Vec<Document> {
+  assert!(limit >= 0, "A search limit can't be negative");
+  if let User(user_id) = user_id {
+    assert!(operation_is_allowed(user_id, &Operation::List,
+                                 &collection_parent_path,
+                                 collection_id, &None));
+  }
+
+  let query_string = format!(
+    "SELECT D.document_data FROM documents D JOIN (
+       SELECT collection_parent_path, collection_id, document_id, max(ts_rank(search_vector, Q)) AS rank
+       FROM full_text_search_lookup, websearch_to_tsquery('{}', $1) Q
+       WHERE search_vector @@ Q and collection_id = $2 and ($3::TEXT IS NULL or collection_parent_path = $3)
+       GROUP BY collection_parent_path, collection_id, document_id
+     ) R
+     ON D.collection_parent_path = R.collection_parent_path and D.collection_id = R.collection_id and D.document_id = R.document_id
+     ORDER BY R.rank DESC
+     LIMIT $4", TEXT_SEARCH_CONFIG);
+  let rows = transaction.query(
+    &query_string,
+    &[&search_text, &collection_id, &collection_parent_path, &limit],
+  ).unwrap();
+
+  decode_document_rows(&rows, field_mask)
+}
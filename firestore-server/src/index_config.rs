@@ -0,0 +1,181 @@
+use std::fs;
+
+use postgres::Transaction;
+use serde::Deserialize;
+
+use crate::composite_groups::{alter_composite_group, create_composite_group, drop_composite_group, DropSubscriptionPolicy, get_composite_groups, get_replaced_composite_group_ids};
+use crate::composite_query::CompositeFieldGroup;
+use crate::simple_index_rules::{get_simple_index_rules, remove_simple_index_rule, set_simple_index_rule};
+
+// The indexes that should exist, eg.
+//
+// [[composite_groups]]
+// collection_parent_path = "/"
+// collection_id = "users"
+// primary_field = "age"
+// secondary_fields = ["city", "name"]
+// descending_fields = ["age"]
+//
+// [[simple_index_rules]]
+// collection_id = "users"
+// field = "biography"
+// indexed = false
+//
+// Leaving out the collection parent path applies the entry to the whole collection group, and
+// leaving out the field of a simple index rule applies it to every field.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct IndexConfig {
+  #[serde(default)]
+  pub composite_groups: Vec<CompositeGroupConfig>,
+  #[serde(default)]
+  pub simple_index_rules: Vec<SimpleIndexRuleConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CompositeGroupConfig {
+  pub collection_parent_path: Option<String>,
+  pub collection_id: String,
+  pub primary_field: String,
+  pub secondary_fields: Vec<String>,
+  #[serde(default)]
+  pub descending_fields: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SimpleIndexRuleConfig {
+  pub collection_parent_path: Option<String>,
+  pub collection_id: String,
+  pub field: Option<String>,
+  pub indexed: bool,
+}
+
+impl SimpleIndexRuleConfig {
+  fn has_same_scope(&self, other: &SimpleIndexRuleConfig) -> bool {
+    self.collection_parent_path == other.collection_parent_path
+      && self.collection_id == other.collection_id
+      && self.field == other.field
+  }
+}
+
+#[derive(Debug, Clone)]
+pub enum IndexConfigChange {
+  CreateCompositeGroup(CompositeGroupConfig),
+  // A group with the same fields but different directions is rebuilt as a new version, which
+  // only replaces the live group once it is ready
+  AlterCompositeGroup(CompositeFieldGroup, CompositeGroupConfig),
+  DropCompositeGroup(CompositeFieldGroup),
+  SetSimpleIndexRule(SimpleIndexRuleConfig),
+  RemoveSimpleIndexRule(SimpleIndexRuleConfig),
+}
+
+pub fn load_index_config(path: &str) -> IndexConfig {
+  let config_string = fs::read_to_string(path).unwrap();
+  toml::from_str(&config_string).unwrap_or_else(|error| panic!("Invalid index config {}: {}", path, error))
+}
+
+pub fn diff_index_config(transaction: &mut Transaction, config: &IndexConfig) -> Vec<IndexConfigChange> {
+  let mut changes = vec![];
+
+  // A group whose new version is still being built is handled through the new version
+  let replaced_group_ids = get_replaced_composite_group_ids(transaction);
+  let live_groups: Vec<CompositeFieldGroup> = get_composite_groups(transaction).into_iter()
+    .filter(|group| !replaced_group_ids.contains(&group.group_id))
+    .collect();
+  let mut altered_group_ids = vec![];
+  for group_config in &config.composite_groups {
+    if live_groups.iter().any(|group| group_matches_config(group, group_config)) {
+      continue;
+    }
+    let altered_group = live_groups.iter().find(|group| {
+      !altered_group_ids.contains(&group.group_id)
+        && group_has_config_fields(group, group_config)
+        && !config.composite_groups.iter().any(|x| group_matches_config(group, x))
+    });
+    match altered_group {
+      Some(group) => {
+        altered_group_ids.push(group.group_id.clone());
+        changes.push(IndexConfigChange::AlterCompositeGroup(group.clone(), group_config.clone()));
+      }
+      None => changes.push(IndexConfigChange::CreateCompositeGroup(group_config.clone())),
+    }
+  }
+  for group in live_groups {
+    if altered_group_ids.contains(&group.group_id) {
+      continue;
+    }
+    if !config.composite_groups.iter().any(|group_config| group_matches_config(&group, group_config)) {
+      changes.push(IndexConfigChange::DropCompositeGroup(group));
+    }
+  }
+
+  let live_rules: Vec<SimpleIndexRuleConfig> = get_simple_index_rules(transaction).into_iter()
+    .map(|rule| SimpleIndexRuleConfig {
+      collection_parent_path: rule.collection_parent_path,
+      collection_id: rule.collection_id,
+      field: rule.field_name,
+      indexed: rule.is_indexed,
+    })
+    .collect();
+  for rule in &config.simple_index_rules {
+    if !live_rules.contains(rule) {
+      changes.push(IndexConfigChange::SetSimpleIndexRule(rule.clone()));
+    }
+  }
+  for rule in live_rules {
+    if !config.simple_index_rules.iter().any(|x| x.has_same_scope(&rule)) {
+      changes.push(IndexConfigChange::RemoveSimpleIndexRule(rule));
+    }
+  }
+
+  changes
+}
+
+// Applies the changes and returns the ids of the created groups and group versions. Their
+// backfills should be started once the transaction commits.
+pub fn apply_index_config_changes(
+  transaction: &mut Transaction,
+  changes: &[IndexConfigChange],
+  subscription_policy: &DropSubscriptionPolicy,
+) -> Vec<String> {
+  let mut created_group_ids = vec![];
+  for change in changes {
+    match change {
+      IndexConfigChange::CreateCompositeGroup(group_config) => {
+        let composite_group = create_composite_group(
+          transaction, &group_config.collection_parent_path, &group_config.collection_id,
+          &group_config.primary_field, &group_config.secondary_fields, &group_config.descending_fields);
+        created_group_ids.push(composite_group.group_id);
+      }
+      IndexConfigChange::AlterCompositeGroup(composite_group, group_config) => {
+        let composite_group = alter_composite_group(
+          transaction, &composite_group.group_id, &group_config.secondary_fields, &group_config.descending_fields);
+        created_group_ids.push(composite_group.group_id);
+      }
+      IndexConfigChange::DropCompositeGroup(composite_group) => drop_composite_group(
+        transaction, &composite_group.group_id, subscription_policy,
+        "The composite group was removed from the index config"),
+      IndexConfigChange::SetSimpleIndexRule(rule) => set_simple_index_rule(
+        transaction, &rule.collection_parent_path, &rule.collection_id, &rule.field, rule.indexed),
+      IndexConfigChange::RemoveSimpleIndexRule(rule) => remove_simple_index_rule(
+        transaction, &rule.collection_parent_path, &rule.collection_id, &rule.field),
+    }
+  }
+  created_group_ids
+}
+
+pub(crate) fn group_matches_config(composite_group: &CompositeFieldGroup, group_config: &CompositeGroupConfig) -> bool {
+  let mut descending_fields = group_config.descending_fields.clone();
+  descending_fields.sort();
+  group_has_config_fields(composite_group, group_config)
+    && composite_group.descending_field_names == descending_fields
+}
+
+// Whether the group indexes the same collection and fields as the config, in any direction
+fn group_has_config_fields(composite_group: &CompositeFieldGroup, group_config: &CompositeGroupConfig) -> bool {
+  let mut secondary_fields = group_config.secondary_fields.clone();
+  secondary_fields.sort();
+  composite_group.collection_parent_path == group_config.collection_parent_path
+    && composite_group.collection_id == group_config.collection_id
+    && composite_group.primary_field_name == group_config.primary_field
+    && composite_group.sorted_secondary_field_names == secondary_fields
+}
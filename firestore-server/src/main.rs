@@ -3,6 +3,7 @@ use std::env;
 use std::error::Error;
 use std::fmt;
 use std::process::Command;
+use std::time::Duration;
 
 use bytes::{Bytes, BytesMut};
 use itertools::Itertools;
@@ -21,11 +22,16 @@ use protos::document_protos::FieldValue;
 use sql_types::field_value;
 
 use crate::basic_read::{get_document, get_documents, get_documents_from_collection_group, subscribe_to_collection, subscribe_to_collection_group, subscribe_to_document};
-use crate::composite_query::{composite_query, CompositeFieldGroup, CompositeFieldGroupType, QueryParameter, subscribe_to_composite_query};
+use crate::composite_groups::{backfill_composite_group_batch, create_composite_group, DropSubscriptionPolicy, get_composite_group, get_composite_groups, spawn_composite_group_backfill, spawn_composite_group_backfill_worker};
+use crate::composite_query::CompositeGroupState;
+use crate::document_id_query::DocumentIdField;
+use crate::index_config::{apply_index_config_changes, diff_index_config, load_index_config};
+use crate::query::{collection, Op, OrderField};
+use crate::query_explain::{explain_composite_subscription_matching, explain_simple_query_subscription_matching};
+use crate::query_stats::{index_advisor_report, start_recording_query_stats};
 use crate::security_rules::UserId;
-use crate::simple_query::simple_query;
-use crate::simple_query::subscribe_to_simple_query;
 use crate::sql_types::Unit;
+use crate::subscriptions::spawn_subscription_reaper;
 use crate::write::{delete_document, write_document};
 
 pub mod protos;
@@ -33,18 +39,38 @@ mod sql_types;
 mod basic_read;
 mod write;
 mod simple_query;
+mod simple_index_rules;
 mod composite_query;
+mod composite_groups;
+mod subscriptions;
 mod utils;
 mod security_rules;
 mod update_queue;
 mod client_connection_endpoint;
 mod transaction;
+mod document_stream;
+mod query_explain;
+mod full_text_search;
+mod document_id_query;
+mod query;
+mod index_config;
+mod query_stats;
 mod post;
 
 // create an alias for a Result that can contain any error
 type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
 fn main() -> Result<()> {
+  let args: Vec<String> = env::args().collect();
+  if args.len() >= 3 && args[1] == "apply-index-config" {
+    apply_index_config(&args[2], args.iter().any(|x| x == "--dry-run"));
+    return Ok(());
+  }
+  if args.len() >= 2 && args[1] == "index-advisor" {
+    print_index_advisor_report();
+    return Ok(());
+  }
+
   println!("{:?}", (((i64::MAX - 1) as f64) as i64) == i64::MAX - 1);
   println!("{:?}", ((f64::MAX) as i64) as f64);
   println!("{:?}", ((f64::MAX) as i64) == i64::MAX);
@@ -57,12 +83,20 @@ fn main() -> Result<()> {
   Ok(())
 }
 
+// The workers run for as long as the server does
+fn start_background_workers(connection_string: &str) {
+  spawn_composite_group_backfill_worker(connection_string.to_string(), Duration::from_secs(1));
+  start_recording_query_stats(connection_string);
+  spawn_subscription_reaper(connection_string.to_string(), Duration::from_secs(60));
+}
+
 fn mainish() {
   teardown_database();
   setup_database();
 
   let user: String = env::var("USER").unwrap();
   let connection_string = &format!("host=localhost user={} dbname=diy_firestore", user);
+  start_background_workers(connection_string);
   let mut client: Client = Client::connect(connection_string, NoTls).unwrap();
   let mut transaction = client.build_transaction()
     .isolation_level(IsolationLevel::Serializable)
@@ -85,7 +119,8 @@ fn mainish() {
     &user_doc_id_1.collection_parent_path,
     &user_doc_id_1.collection_id,
     &user_doc_id_1.document_id,
-  );
+    &None,
+  ).subscription_id;
 
   let collection_subscription_id = subscribe_to_collection(
     &mut transaction,
@@ -93,41 +128,29 @@ fn mainish() {
     &user_id,
     &user_doc_id_1.collection_parent_path,
     &user_doc_id_1.collection_id,
-  );
+    &None,
+  ).subscription_id;
 
   let collection_group_subscription_id = subscribe_to_collection_group(
     &mut transaction,
     &client_id,
     &user_id,
     "posts",
-  );
+    &None,
+  ).subscription_id;
 
 
   let mut age_field_value_25 = field_value::default();
   age_field_value_25.integer_value = Some(25);
-  let simple_user_age_subscription_id = subscribe_to_simple_query(
-    &mut transaction,
-    &client_id,
-    &user_id,
-    &Some(user_doc_id_1.collection_parent_path.clone()),
-    &user_doc_id_1.collection_id,
-    "age",
-    "=",
-    &age_field_value_25,
-  );
+  let simple_user_age_subscription_id = collection(&user_doc_id_1.collection_parent_path, &user_doc_id_1.collection_id)
+    .where_("age", Op::Eq, age_field_value_25.clone())
+    .subscribe(&mut transaction, &client_id, &user_id, &None).subscription_id;
 
   let mut name_field_value = field_value::default();
   name_field_value.string_value = Some("Quinn".to_string());
-  let simple_user_name_subscription_id = subscribe_to_simple_query(
-    &mut transaction,
-    &client_id,
-    &user_id,
-    &Some(user_doc_id_1.collection_parent_path.clone()),
-    &user_doc_id_1.collection_id,
-    "name",
-    "=",
-    &name_field_value,
-  );
+  let simple_user_name_subscription_id = collection(&user_doc_id_1.collection_parent_path, &user_doc_id_1.collection_id)
+    .where_("name", Op::Eq, name_field_value.clone())
+    .subscribe(&mut transaction, &client_id, &user_id, &None).subscription_id;
 
   let mut age_field_value_130 = field_value::default();
   age_field_value_130.integer_value = Some(130);
@@ -138,53 +161,30 @@ fn mainish() {
   let mut zipcode_field_value = field_value::default();
   zipcode_field_value.string_value = Some("20390".to_string());
 
-  let composite_field_group = CompositeFieldGroup {
-    collection_parent_path: Some("/".to_string()),
-    collection_id: "users".to_string(),
-    group_id: "d8b8c614b73546daa1d85531dc412ef6".to_string(),
-    primary_field_name: "age".to_string(),
-    sorted_secondary_field_names: vec!["city".to_string(), "name".to_string(), "zipcode".to_string()],
-  };
-  let parameters = vec![
-    QueryParameter {
-      field_name: "age".to_string(),
-      operator: ">=".to_owned(),
-      parameter: age_field_value_25.clone(),
-      is_primary: true,
-    },
-    QueryParameter {
-      field_name: "age".to_string(),
-      operator: "<".to_owned(),
-      parameter: age_field_value_130.clone(),
-      is_primary: true,
-    },
-    QueryParameter {
-      field_name: "city".to_string(),
-      operator: "=".to_owned(),
-      parameter: city_field_value.clone(),
-      is_primary: false,
-    },
-    QueryParameter {
-      field_name: "name".to_string(),
-      operator: "=".to_owned(),
-      parameter: name_field_value.clone(),
-      is_primary: false,
-    },
-    QueryParameter {
-      field_name: "zipcode".to_string(),
-      operator: "=".to_owned(),
-      parameter: zipcode_field_value.clone(),
-      is_primary: false,
-    },
-  ];
-
-  let composite_subscription_id = subscribe_to_composite_query(
+  let composite_field_group = create_composite_group(
+    &mut transaction,
+    &Some("/".to_string()),
+    "users",
+    "age",
+    &["city".to_string(), "name".to_string(), "zipcode".to_string()],
+    &[],
+  );
+  // The collection is still empty, so a single batch finishes the backfill
+  backfill_composite_group_batch(&mut transaction, &composite_field_group.group_id, 100);
+  let composite_field_group = get_composite_group(&mut transaction, &composite_field_group.group_id).unwrap();
+  let composite_user_query = collection("/", "users")
+    .where_("age", Op::Gte, age_field_value_25.clone())
+    .where_("age", Op::Lt, age_field_value_130.clone())
+    .where_("city", Op::Eq, city_field_value.clone())
+    .where_("name", Op::Eq, name_field_value.clone())
+    .where_("zipcode", Op::Eq, zipcode_field_value.clone());
+
+  let composite_subscription_id = composite_user_query.subscribe(
     &mut transaction,
     &client_id,
     &user_id,
-    &parameters,
-    &composite_field_group,
-  );
+    &None,
+  ).subscription_id;
 
   let mut user_1 = Document {
     id: Some(user_doc_id_1.clone()),
@@ -281,19 +281,19 @@ fn mainish() {
   };
 
 
-  write_document(&mut transaction, &user_id, user_1.clone(), &vec![composite_field_group.clone()]);
+  write_document(&mut transaction, &user_id, user_1.clone());
   user_1.fields.insert("name".to_string(), FieldValue { value: Some(StringValue("Jack".to_string())) });
   user_1.fields.insert("age".to_string(), FieldValue { value: Some(IntegerValue(26)) });
-  write_document(&mut transaction, &user_id, user_1.clone(), &vec![composite_field_group.clone()]);
-  write_document(&mut transaction, &user_id, user_2.clone(), &vec![composite_field_group.clone()]);
-  write_document(&mut transaction, &user_id, user_3.clone(), &vec![composite_field_group.clone()]);
-  write_document(&mut transaction, &user_id, user_4.clone(), &vec![composite_field_group.clone()]);
-  write_document(&mut transaction, &user_id, user_5.clone(), &vec![composite_field_group.clone()]);
-  write_document(&mut transaction, &user_id, user_6.clone(), &vec![composite_field_group.clone()]);
-  write_document(&mut transaction, &user_id, post_1.clone(), &vec![]);
-  write_document(&mut transaction, &user_id, post_2.clone(), &vec![]);
+  write_document(&mut transaction, &user_id, user_1.clone());
+  write_document(&mut transaction, &user_id, user_2.clone());
+  write_document(&mut transaction, &user_id, user_3.clone());
+  write_document(&mut transaction, &user_id, user_4.clone());
+  write_document(&mut transaction, &user_id, user_5.clone());
+  write_document(&mut transaction, &user_id, user_6.clone());
+  write_document(&mut transaction, &user_id, post_1.clone());
+  write_document(&mut transaction, &user_id, post_2.clone());
 
-  delete_document(&mut transaction, &user_id, &user_doc_id_4.collection_parent_path, &user_doc_id_4.collection_id, &user_doc_id_4.document_id, &vec![composite_field_group.clone()]);
+  delete_document(&mut transaction, &user_id, &user_doc_id_4.collection_parent_path, &user_doc_id_4.collection_id, &user_doc_id_4.document_id);
 
 
   println!("document_subscription_id");
@@ -322,23 +322,18 @@ fn mainish() {
   println!();
 
 
-  println!("{:?}", get_document(&mut transaction, &user_id, "/", "users", "AAA"));
+  println!("{:?}", get_document(&mut transaction, &user_id, "/", "users", "AAA", &None));
   println!();
-  println!("{:?}", get_documents(&mut transaction, &user_id, "/", "users"));
+  println!("{:?}", get_documents(&mut transaction, &user_id, "/", "users", &Some(vec!["name".to_string(), "age".to_string()])));
   println!();
-  println!("{:?}", get_documents_from_collection_group(&mut transaction, &user_id, "posts"));
+  println!("{:?}", get_documents_from_collection_group(&mut transaction, &user_id, "posts", &None));
 
   let mut age_field_value_30 = field_value::default();
   age_field_value_30.integer_value = Some(25);
-  let simple_query_age_result = simple_query(
-    &mut transaction,
-    &user_id,
-    &Some(user_doc_id_1.collection_parent_path.clone()),
-    &user_doc_id_1.collection_id,
-    "age",
-    ">",
-    &age_field_value_30,
-  );
+  let simple_query_age_result = collection(&user_doc_id_1.collection_parent_path, &user_doc_id_1.collection_id)
+    .where_("age", Op::Gt, age_field_value_30.clone())
+    .order_by(OrderField::DocumentId, false)
+    .get(&mut transaction, &user_id);
   for doc in simple_query_age_result {
     println!("{:?}", doc);
   }
@@ -346,32 +341,34 @@ fn mainish() {
 
   let mut name_field_value_avery = field_value::default();
   name_field_value_avery.string_value = Some("Avery".to_string());
-  let simple_query_name_result = simple_query(
-    &mut transaction,
-    &user_id,
-    &Some(user_doc_id_1.collection_parent_path.clone()),
-    &user_doc_id_1.collection_id,
-    "name",
-    "=",
-    &name_field_value_avery,
-  );
+  let simple_query_name_result = collection(&user_doc_id_1.collection_parent_path, &user_doc_id_1.collection_id)
+    .where_("name", Op::Eq, name_field_value_avery.clone())
+    .where_document_id(DocumentIdField::DocumentId, Op::In, vec!["AAA".to_string(), "CCC".to_string()])
+    .get(&mut transaction, &user_id);
   for doc in simple_query_name_result {
     println!("{:?}", doc);
   }
   println!();
 
 
-  let composite_query_result = composite_query(
-    &mut transaction,
-    &user_id,
-    &parameters,
-    &composite_field_group,
-  );
+  let composite_query_result = composite_user_query.get(&mut transaction, &user_id);
   for doc in composite_query_result {
     println!("{:?}", doc);
   }
   println!();
 
+  // Confirm indexes are being used
+  println!("{:?}", collection(&user_doc_id_1.collection_parent_path, &user_doc_id_1.collection_id)
+    .where_("age", Op::Gt, age_field_value_30.clone())
+    .explain(&mut transaction, &user_id));
+  println!("{:?}", composite_user_query.explain(&mut transaction, &user_id));
+  for explanation in explain_simple_query_subscription_matching(
+    &mut transaction, &user_doc_id_2.collection_parent_path, &user_doc_id_2.collection_id, &user_2) {
+    println!("{:?}", explanation);
+  }
+  println!("{:?}", explain_composite_subscription_matching(&mut transaction, &user_2, &composite_field_group));
+  println!();
+
 
   transaction.commit().unwrap();
 
@@ -382,7 +379,6 @@ fn mainish() {
   // Read doc
   // Query docs
   // Composite query
-  // Confirm indexes are being used by entering sql queries in terminal
 }
 
 
@@ -414,7 +410,8 @@ fn basic_subscription() {
     &user_doc_id_1.collection_parent_path,
     &user_doc_id_1.collection_id,
     &user_doc_id_1.document_id,
-  );
+    &None,
+  ).subscription_id;
 
   let mut user_1 = Document {
     id: Some(user_doc_id_1.clone()),
@@ -426,9 +423,9 @@ fn basic_subscription() {
     update_id: None,
   };
 
-  write_document(&mut transaction, &user_id, user_1.clone(), &vec![]);
+  write_document(&mut transaction, &user_id, user_1.clone());
   user_1.fields.insert("name".to_string(), FieldValue { value: Some(StringValue("Jack".to_string())) });
-  write_document(&mut transaction, &user_id, user_1.clone(), &vec![]);
+  write_document(&mut transaction, &user_id, user_1.clone());
 
 
   println!("document_subscription_id");
@@ -437,6 +434,54 @@ fn basic_subscription() {
 }
 
 
+// Diffs the index config file against the live catalog, prints the changes and, unless it is a
+// dry run, applies them and backfills every group that is still being built
+fn apply_index_config(config_path: &str, dry_run: bool) {
+  let user: String = env::var("USER").unwrap();
+  let connection_string = format!("host=localhost user={} dbname=diy_firestore", user);
+  let mut client: Client = Client::connect(&connection_string, NoTls).unwrap();
+
+  let config = load_index_config(config_path);
+  let mut transaction = client.transaction().unwrap();
+  let changes = diff_index_config(&mut transaction, &config);
+  for change in changes.iter() {
+    println!("{:?}", change);
+  }
+  if dry_run {
+    return;
+  }
+  apply_index_config_changes(&mut transaction, &changes, &DropSubscriptionPolicy::Fail);
+  let building_group_ids: Vec<String> = get_composite_groups(&mut transaction).into_iter()
+    .filter(|group| group.state == CompositeGroupState::Building)
+    .map(|group| group.group_id)
+    .collect();
+  transaction.commit().unwrap();
+
+  let backfills: Vec<_> = building_group_ids.into_iter()
+    .map(|group_id| spawn_composite_group_backfill(connection_string.clone(), group_id))
+    .collect();
+  for backfill in backfills {
+    backfill.join().unwrap();
+  }
+}
+
+fn print_index_advisor_report() {
+  let user: String = env::var("USER").unwrap();
+  let connection_string = format!("host=localhost user={} dbname=diy_firestore", user);
+  let mut client: Client = Client::connect(&connection_string, NoTls).unwrap();
+  let mut transaction = client.transaction().unwrap();
+
+  let report = index_advisor_report(&mut transaction, 1, 100.0);
+  println!("Suggested composite groups");
+  for suggestion in report.suggested_groups {
+    println!("{:?}", suggestion);
+  }
+  println!("Unused composite groups");
+  for composite_group in report.unused_groups {
+    println!("{:?}", composite_group);
+  }
+}
+
 fn setup_database() {
   let home_dir: String =
     env::vars().filter(|&(ref k, _)|
@@ -445,12 +490,10 @@ fn setup_database() {
 
   let create_composite_type_path = home_dir.clone() + "/diy-firestore/sql-setup/create_composite_type.sql";
   let create_tables_path = home_dir.clone() + "/diy-firestore/sql-setup/create_tables.sql";
-  let create_sample_composite_tables_path = home_dir.clone() + "/diy-firestore/sql-setup/create_sample_composite_group_tables.sql";
 
   Command::new("createdb").arg("diy_firestore").output().unwrap();
   Command::new("psql").args(["-d", "diy_firestore", "-f", &create_composite_type_path]).output().unwrap();
   Command::new("psql").args(["-d", "diy_firestore", "-f", &create_tables_path]).output().unwrap();
-  Command::new("psql").args(["-d", "diy_firestore", "-f", &create_sample_composite_tables_path]).output().unwrap();
 }
 
 fn teardown_database() {
@@ -461,7 +504,8 @@ fn get_subscription_updates(transaction: &mut Transaction, subscription_id: &str
   let update_docs: Vec<(String, Option<Vec<u8>>)> = transaction.query(
     "SELECT document_id, document_data
     from update_queues
-    where subscription_id = $1",
+    where subscription_id = $1
+    order by queue_position",
     &[&subscription_id],
   ).unwrap().into_iter().map(|x| (x.get(0), x.get(1))).collect();
   // println!("{:?}", update_docs);
@@ -0,0 +1,386 @@
+use std::time::Instant;
+
+use postgres::Transaction;
+
+use crate::composite_groups::{get_composite_groups, get_composite_groups_for_collection};
+use crate::composite_query::{CompositeFieldGroup, CompositeGroupState};
+use crate::document_id_query::{document_id_order_clause, DocumentIdField, DocumentIdFilter};
+use crate::document_stream::DocumentStream;
+use crate::protos::document_protos::Document;
+use crate::query_explain::QueryExplanation;
+use crate::query_stats::{QueryShape, record_query_shape};
+use crate::security_rules::UserId;
+use crate::sql_types::field_value;
+use crate::update_queue::{ResumePoint, Subscription};
+use crate::utils::{CROSS_TYPE_OPERATOR_PREFIX, EXISTS_OPERATOR, is_type_operator, NOT_EXISTS_OPERATOR, STARTS_WITH_OPERATOR};
+
+use self::execution::{composite_query, explain_composite_query, explain_simple_query, QueryParameter, simple_query,
+                      stream_composite_query, stream_simple_query, subscribe_to_composite_query, subscribe_to_simple_query};
+
+mod execution;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldType {
+  Null,
+  Boolean,
+  Integer,
+  Double,
+  // Matches both integers and doubles
+  Number,
+  Timestamp,
+  String,
+  Bytes,
+  Reference,
+}
+
+impl FieldType {
+  fn name(&self) -> &'static str {
+    match self {
+      FieldType::Null => "null",
+      FieldType::Boolean => "boolean",
+      FieldType::Integer => "integer",
+      FieldType::Double => "double",
+      FieldType::Number => "number",
+      FieldType::Timestamp => "timestamp",
+      FieldType::String => "string",
+      FieldType::Bytes => "bytes",
+      FieldType::Reference => "reference",
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+  Lt,
+  Lte,
+  Eq,
+  Neq,
+  Gt,
+  Gte,
+  // Inequalities that match values of every type rather than only the type of the query value
+  CrossTypeLt,
+  CrossTypeLte,
+  CrossTypeGt,
+  CrossTypeGte,
+  StartsWith,
+  // Exists, NotExists and IsType ignore the query value
+  Exists,
+  NotExists,
+  IsType(FieldType),
+  // Only supported on document ids
+  In,
+}
+
+impl Op {
+  // The operator string used by the simple and composite query machinery
+  pub fn as_operator(&self) -> String {
+    match self {
+      Op::Lt => "<".to_owned(),
+      Op::Lte => "<=".to_owned(),
+      Op::Eq => "=".to_owned(),
+      Op::Neq => "!=".to_owned(),
+      Op::Gt => ">".to_owned(),
+      Op::Gte => ">=".to_owned(),
+      Op::CrossTypeLt => format!("{}<", CROSS_TYPE_OPERATOR_PREFIX),
+      Op::CrossTypeLte => format!("{}<=", CROSS_TYPE_OPERATOR_PREFIX),
+      Op::CrossTypeGt => format!("{}>", CROSS_TYPE_OPERATOR_PREFIX),
+      Op::CrossTypeGte => format!("{}>=", CROSS_TYPE_OPERATOR_PREFIX),
+      Op::StartsWith => STARTS_WITH_OPERATOR.to_owned(),
+      Op::Exists => EXISTS_OPERATOR.to_owned(),
+      Op::NotExists => NOT_EXISTS_OPERATOR.to_owned(),
+      Op::IsType(field_type) => is_type_operator(field_type.name()),
+      Op::In => "in".to_owned(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OrderField {
+  Field(String),
+  DocumentId,
+  FullPath,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueryOrder {
+  pub field: OrderField,
+  pub descending: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct QueryOptions {
+  pub document_id_filters: Vec<DocumentIdFilter>,
+  pub order_by: Vec<QueryOrder>,
+  pub limit: Option<i64>,
+}
+
+// Builds the ORDER BY and LIMIT clauses of a query. field_column maps an ordered field name to
+// its column, and should panic if the query can't be ordered by the field.
+pub fn order_by_and_limit_clause(query_options: &QueryOptions, field_column: impl Fn(&str) -> String) -> String {
+  let mut clause = String::new();
+  let order_terms: Vec<String> = query_options.order_by.iter()
+    .map(|order| match &order.field {
+      OrderField::Field(field_name) =>
+        format!("{} {}", field_column(field_name), if order.descending { "DESC" } else { "ASC" }),
+      OrderField::DocumentId => document_id_order_clause("D", &DocumentIdField::DocumentId, order.descending),
+      OrderField::FullPath => document_id_order_clause("D", &DocumentIdField::FullPath, order.descending),
+    })
+    .collect();
+  if !order_terms.is_empty() {
+    clause.push_str(&format!(" ORDER BY {}", order_terms.join(", ")));
+  }
+  if let Some(limit) = query_options.limit {
+    clause.push_str(&format!(" LIMIT {}", limit));
+  }
+  clause
+}
+
+#[derive(Debug, Clone)]
+struct QueryFilter {
+  field_name: String,
+  op: Op,
+  value: field_value,
+}
+
+// A query on a collection or collection group, eg.
+//   collection("/", "users").where_("age", Op::Gte, age).order_by(OrderField::Field("age".to_owned()), false).limit(10)
+// A query with filters on a single field runs as a simple query. Any other query runs against the
+// registered composite field group that covers its filters.
+#[derive(Debug, Clone)]
+pub struct Query {
+  collection_parent_path: Option<String>,
+  collection_id: String,
+  filters: Vec<QueryFilter>,
+  options: QueryOptions,
+  field_mask: Option<Vec<String>>,
+}
+
+pub fn collection(collection_parent_path: &str, collection_id: &str) -> Query {
+  Query {
+    collection_parent_path: Some(collection_parent_path.to_owned()),
+    collection_id: collection_id.to_owned(),
+    filters: vec![],
+    options: QueryOptions::default(),
+    field_mask: None,
+  }
+}
+
+pub fn collection_group(collection_id: &str) -> Query {
+  Query {
+    collection_parent_path: None,
+    collection_id: collection_id.to_owned(),
+    filters: vec![],
+    options: QueryOptions::default(),
+    field_mask: None,
+  }
+}
+
+enum QueryPlan<'a> {
+  // The filters are all on the same field
+  Simple(&'a str, Vec<(Op, field_value)>),
+  Composite(Vec<QueryParameter>, CompositeFieldGroup),
+}
+
+impl QueryPlan<'_> {
+  fn composite_group_id(&self) -> Option<String> {
+    match self {
+      QueryPlan::Simple(_, _) => None,
+      QueryPlan::Composite(_, composite_group) => Some(composite_group.group_id.clone()),
+    }
+  }
+}
+
+impl Query {
+  pub fn where_(mut self, field_name: &str, op: Op, value: field_value) -> Query {
+    assert!(op != Op::In, "The in operator is only supported on document ids");
+    assert!(op != Op::StartsWith || value.string_value.is_some() || value.bytes_value.is_some(),
+            "The starts-with operator requires a string or bytes value");
+    self.filters.push(QueryFilter {
+      field_name: field_name.to_owned(),
+      op,
+      value,
+    });
+    self
+  }
+
+  pub fn where_document_id(mut self, field: DocumentIdField, op: Op, values: Vec<String>) -> Query {
+    match op {
+      Op::Lt | Op::Lte | Op::Eq | Op::Neq | Op::Gt | Op::Gte | Op::In => {}
+      _ => panic!("The {:?} operator is not supported on document ids", op),
+    }
+    self.options.document_id_filters.push(DocumentIdFilter {
+      field,
+      operator: op.as_operator(),
+      values,
+    });
+    self
+  }
+
+  pub fn order_by(mut self, field: OrderField, descending: bool) -> Query {
+    self.options.order_by.push(QueryOrder { field, descending });
+    self
+  }
+
+  pub fn limit(mut self, limit: i64) -> Query {
+    assert!(limit >= 0, "A query limit can't be negative");
+    self.options.limit = Some(limit);
+    self
+  }
+
+  pub fn select(mut self, field_mask: Vec<String>) -> Query {
+    self.field_mask = Some(field_mask);
+    self
+  }
+
+  pub fn get(
+    &self,
+    transaction: &mut Transaction,
+    user_id: &UserId,
+  ) -> Vec<Document> {
+    let plan = self.plan(transaction, false);
+    let composite_group_id = plan.composite_group_id();
+    let start_time = Instant::now();
+    let documents = match plan {
+      QueryPlan::Simple(field_name, filters) => simple_query(
+        transaction, user_id, &self.collection_parent_path, &self.collection_id,
+        field_name, &filters, &self.options, &self.field_mask),
+      QueryPlan::Composite(parameters, composite_group) => composite_query(
+        transaction, user_id, &parameters, &composite_group, &self.options, &self.field_mask),
+    };
+    record_query_shape(&self.shape(), false, &composite_group_id, false, Some(start_time.elapsed()));
+    documents
+  }
+
+  pub fn stream<'a, 'b>(
+    &self,
+    transaction: &'a mut Transaction<'b>,
+    user_id: &UserId,
+    batch_size: i32,
+  ) -> DocumentStream<'a, 'b> {
+    let plan = self.plan(transaction, false);
+    record_query_shape(&self.shape(), false, &plan.composite_group_id(), false, None);
+    match plan {
+      QueryPlan::Simple(field_name, filters) => stream_simple_query(
+        transaction, user_id, &self.collection_parent_path, &self.collection_id,
+        field_name, &filters, &self.options, &self.field_mask, batch_size),
+      QueryPlan::Composite(parameters, composite_group) => stream_composite_query(
+        transaction, user_id, &parameters, &composite_group, &self.options, &self.field_mask, batch_size),
+    }
+  }
+
+  pub fn explain(
+    &self,
+    transaction: &mut Transaction,
+    user_id: &UserId,
+  ) -> QueryExplanation {
+    match self.plan(transaction, false) {
+      QueryPlan::Simple(field_name, filters) => explain_simple_query(
+        transaction, user_id, &self.collection_parent_path, &self.collection_id,
+        field_name, &filters, &self.options),
+      QueryPlan::Composite(parameters, composite_group) => explain_composite_query(
+        transaction, user_id, &parameters, &composite_group, &self.options),
+    }
+  }
+
+  pub fn subscribe(
+    &self,
+    transaction: &mut Transaction,
+    client_id: &str,
+    user_id: &UserId,
+    resume_point: &Option<ResumePoint>,
+  ) -> Subscription {
+    assert!(self.options.document_id_filters.is_empty(), "Subscriptions do not support document id filters");
+    assert!(self.options.limit.is_none(), "Subscriptions do not support limits");
+
+    let plan = self.plan(transaction, true);
+    record_query_shape(&self.shape(), true, &plan.composite_group_id(), false, None);
+    match plan {
+      QueryPlan::Simple(field_name, filters) => subscribe_to_simple_query(
+        transaction, client_id, user_id, &self.collection_parent_path, &self.collection_id,
+        field_name, &filters[0].0, &filters[0].1, resume_point),
+      QueryPlan::Composite(parameters, composite_group) => subscribe_to_composite_query(
+        transaction, client_id, user_id, &parameters, &composite_group, resume_point),
+    }
+  }
+
+  // Queries run on the composite groups registered in the catalog, so a group that is still
+  // being built is never used. A simple subscription is matched on a single filter, so a
+  // subscription with several filters on the same field still needs a composite group.
+  fn plan(&self, transaction: &mut Transaction, for_subscription: bool) -> QueryPlan<'_> {
+    assert!(!self.filters.is_empty(), "A query requires at least one field filter");
+    let field_name = &self.filters[0].field_name;
+    let is_single_field = self.filters.iter().all(|filter| filter.field_name == *field_name);
+
+    // Missing fields are stored as null in the composite lookup tables, so existence can only be
+    // checked by a simple query, and composite subscriptions match their primary field on a range
+    if self.filters.len() > 1 {
+      for filter in &self.filters {
+        assert!(!matches!(filter.op, Op::Exists | Op::NotExists),
+                "The {:?} filter on {} is only supported in a query with no other filters", filter.op, filter.field_name);
+        assert!(!for_subscription || !matches!(filter.op, Op::IsType(_)),
+                "The {:?} filter on {} is only supported in a subscription with no other filters", filter.op, filter.field_name);
+      }
+    }
+
+    if self.filters.len() == 1 || (is_single_field && !for_subscription) {
+      let filters = self.filters.iter().map(|filter| (filter.op, filter.value.clone())).collect();
+      return QueryPlan::Simple(field_name, filters);
+    }
+
+    let composite_groups = match &self.collection_parent_path {
+      Some(collection_parent_path) => get_composite_groups_for_collection(transaction, collection_parent_path, &self.collection_id),
+      None => get_composite_groups(transaction),
+    };
+    let composite_group = composite_groups.into_iter()
+      .filter(|group| group.state == CompositeGroupState::Ready)
+      .find(|group| self.is_covered_by(group, for_subscription))
+      .unwrap_or_else(|| {
+        record_query_shape(&self.shape(), for_subscription, &None, true, None);
+        panic!("No composite field group covers the query on {}", self.collection_id)
+      });
+
+    let parameters = self.filters.iter()
+      .map(|filter| QueryParameter {
+        field_name: filter.field_name.clone(),
+        op: filter.op,
+        parameter: filter.value.clone(),
+        is_primary: filter.field_name == composite_group.primary_field_name,
+      })
+      .collect();
+    QueryPlan::Composite(parameters, composite_group)
+  }
+
+  fn shape(&self) -> QueryShape {
+    let filters: Vec<(String, String)> = self.filters.iter()
+      .map(|filter| (filter.field_name.clone(), filter.op.as_operator()))
+      .collect();
+    QueryShape::new(&self.collection_parent_path, &self.collection_id, &filters)
+  }
+
+  // Secondary fields only support equality and prefix filters. Subscriptions match on every
+  // secondary field, so they must filter on all of them.
+  fn is_covered_by(&self, composite_group: &CompositeFieldGroup, for_subscription: bool) -> bool {
+    if composite_group.collection_id != self.collection_id
+      || composite_group.collection_parent_path != self.collection_parent_path {
+      return false;
+    }
+
+    for filter in &self.filters {
+      if filter.field_name == composite_group.primary_field_name {
+        continue;
+      }
+      if !composite_group.has_field(&filter.field_name) {
+        return false;
+      }
+      if filter.op != Op::Eq && filter.op != Op::StartsWith {
+        return false;
+      }
+    }
+
+    if for_subscription {
+      return composite_group.sorted_secondary_field_names.iter()
+        .all(|field_name| self.filters.iter().any(|filter| &filter.field_name == field_name));
+    }
+    true
+  }
+}
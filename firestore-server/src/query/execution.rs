@@ -0,0 +1,410 @@
+use postgres::Transaction;
+use postgres::types::ToSql;
+use sql_query_builder;
+use uuid::Uuid;
+
+use crate::basic_read::decode_document_rows;
+use crate::composite_groups::composite_group_is_ready;
+use crate::composite_query::{add_document_to_composite_subscription_result, CompositeFieldGroup};
+use crate::document_id_query::document_id_constraints;
+use crate::document_stream::{bind_document_stream, DocumentStream};
+use crate::protos::document_protos::Document;
+use crate::query_explain::{explain, QueryExplanation, SIMPLE_QUERY_INDEX_NAME};
+use crate::security_rules::{Operation, operation_is_allowed, UserId};
+use crate::security_rules::UserId::User;
+use crate::simple_index_rules::assert_field_is_indexed;
+use crate::sql_types::field_value;
+use crate::subscriptions::{readable_documents, register_client_subscription};
+use crate::update_queue::{ResumePoint, Subscription, write_initial_updates};
+use crate::utils::{field_value_constraint, quote_identifier, type_range_bounds};
+
+use super::{Op, order_by_and_limit_clause, OrderField, QueryOptions, QueryOrder};
+
+// The query entry points behind the Query builder. They are only reachable from query.rs, which
+// has already checked the filters, so every filter is one the simple or composite tables support.
+
+#[derive(Debug, Clone)]
+pub(super) struct QueryParameter {
+  pub field_name: String,
+  pub op: Op,
+  pub parameter: field_value,
+  pub is_primary: bool,
+}
+
+pub(super) fn simple_query(
+  transaction: &mut Transaction,
+  user_id: &UserId,
+  collection_parent_path: &Option<String>,
+  collection_id: &str,
+  field_name: &str,
+  filters: &[(Op, field_value)],
+  query_options: &QueryOptions,
+  field_mask: &Option<Vec<String>>,
+) -> Vec<Document> {
+  if let User(user_id) = user_id {
+    assert!(operation_is_allowed(user_id, &Operation::List,
+                                 &collection_parent_path,
+                                 collection_id, &None));
+  }
+  assert_field_is_indexed(transaction, collection_parent_path, collection_id, field_name);
+
+  let (query_string, args) = simple_query_statement(collection_parent_path, collection_id, field_name, filters, query_options);
+  let args: Vec<&(dyn ToSql + Sync)> = args.iter().map(|x| x.as_ref()).collect();
+  let rows = transaction.query(&query_string, &args).unwrap();
+  decode_document_rows(&rows, field_mask)
+}
+
+// The filters are all on the field. A not exists filter is the only filter.
+fn simple_query_statement(
+  collection_parent_path: &Option<String>,
+  collection_id: &str,
+  field_name: &str,
+  filters: &[(Op, field_value)],
+  query_options: &QueryOptions,
+) -> (String, Vec<Box<dyn ToSql + Sync>>) {
+  let mut args: Vec<Box<dyn ToSql + Sync>> = vec![];
+  let mut query_string;
+  let is_not_exists = filters[0].0 == Op::NotExists;
+  if is_not_exists {
+    // Documents missing the field have no lookup rows for it, so they are found from the documents table
+    query_string = "SELECT D.document_data from documents D where ".to_owned();
+    if let Some(collection_parent_path) = collection_parent_path {
+      query_string.push_str("D.collection_parent_path = $1 and ");
+      args.push(Box::new(collection_parent_path.clone()));
+    }
+    query_string.push_str(&format!(
+      "D.collection_id = ${} and NOT EXISTS (
+         SELECT 1 from simple_query_lookup S
+         where S.collection_parent_path = D.collection_parent_path and S.collection_id = D.collection_id
+         and S.document_id = D.document_id and S.field_name = ${})", args.len() + 1, args.len() + 2));
+    args.push(Box::new(collection_id.to_owned()));
+    args.push(Box::new(field_name.to_owned()));
+  } else {
+    let query_prefix =
+      "SELECT D.document_data from simple_query_lookup S JOIN documents D
+       ON S.collection_parent_path = D.collection_parent_path and S.collection_id = D.collection_id and S.document_id = D.document_id";
+    if let Some(collection_parent_path) = collection_parent_path {
+      query_string = format!("{} where S.collection_parent_path = $1 and S.collection_id = $2 and S.field_name = $3", query_prefix);
+      args.push(Box::new(collection_parent_path.clone()));
+    } else {
+      query_string = format!("{} where S.collection_id = $1 and S.field_name = $2", query_prefix);
+    }
+    args.push(Box::new(collection_id.to_owned()));
+    args.push(Box::new(field_name.to_owned()));
+
+    for (op, field_value) in filters {
+      let (value_constraint, values) = field_value_constraint("S.field_value", &op.as_operator(), args.len() + 1, field_value);
+      query_string.push_str(&format!(" and {}", value_constraint));
+      args.extend(values.into_iter().map(|x| Box::new(x) as Box<dyn ToSql + Sync>));
+    }
+  }
+
+  let (document_id_constraints, document_id_args) = document_id_constraints("D", &query_options.document_id_filters, args.len() + 1);
+  for constraint in document_id_constraints {
+    query_string.push_str(&format!(" and {}", constraint));
+  }
+  args.extend(document_id_args.into_iter());
+
+  let has_field_column = !is_not_exists;
+  query_string.push_str(&order_by_and_limit_clause(query_options, |order_field_name| {
+    assert!(has_field_column && order_field_name == field_name,
+            "A simple query can only be ordered by its filtered field or document id");
+    "S.field_value".to_owned()
+  }));
+  (query_string, args)
+}
+
+pub(super) fn subscribe_to_simple_query(
+  transaction: &mut Transaction,
+  client_id: &str,
+  user_id: &UserId,
+  collection_parent_path: &Option<String>,
+  collection_id: &str,
+  field_name: &str,
+  op: &Op,
+  field_value: &field_value,
+  resume_point: &Option<ResumePoint>)
+  -> Subscription
+{
+  if let User(user_id) = user_id {
+    assert!(operation_is_allowed(user_id, &Operation::List,
+                                 &collection_parent_path,
+                                 collection_id, &None));
+  }
+  assert_field_is_indexed(transaction, collection_parent_path, collection_id, field_name);
+
+  let subscription_id: String = Uuid::new_v4().as_simple().to_string();
+  register_client_subscription(transaction, &subscription_id, client_id, user_id, collection_parent_path, collection_id,
+                               &None, &None);
+
+  // Collection group subscriptions are matched with collection_parent_path IS NULL
+  let field_operator = op.as_operator();
+  transaction.execute("insert into simple_query_subscriptions values ($1, $2, $3, $4, $5, $6)",
+                      &[&collection_parent_path, &collection_id, &field_name, &field_operator, &field_value, &subscription_id]).unwrap();
+
+  let snapshot = simple_query(transaction, &UserId::Admin, collection_parent_path, collection_id, field_name,
+                              &[(*op, field_value.clone())], &QueryOptions::default(), &None);
+  let snapshot = readable_documents(user_id, snapshot);
+  write_initial_updates(transaction, client_id, &subscription_id, resume_point, collection_parent_path, collection_id, &None, &snapshot, false)
+}
+
+pub(super) fn composite_query(
+  transaction: &mut Transaction,
+  user_id: &UserId,
+  parameters: &[QueryParameter],
+  composite_group: &CompositeFieldGroup,
+  query_options: &QueryOptions,
+  field_mask: &Option<Vec<String>>,
+) -> Vec<Document> {
+  if let User(user_id) = user_id {
+    assert!(operation_is_allowed(user_id, &Operation::List,
+                                 &composite_group.collection_parent_path,
+                                 &composite_group.collection_id, &None));
+  }
+
+  assert!(composite_group_is_ready(transaction, &composite_group.group_id),
+          "The composite group {} is still being built", composite_group.group_id);
+
+  let (query_string, args) = composite_query_statement(parameters, composite_group, query_options);
+  let args: Vec<&(dyn ToSql + Sync)> = args.iter().map(|x| x.as_ref()).collect();
+
+  let rows = transaction.query(&query_string, &args[..]).unwrap();
+  decode_document_rows(&rows, field_mask)
+}
+
+fn composite_query_statement(
+  parameters: &[QueryParameter],
+  composite_group: &CompositeFieldGroup,
+  query_options: &QueryOptions,
+) -> (String, Vec<Box<dyn ToSql + Sync>>) {
+  let mut query = sql_query_builder::Select::new()
+    .select("D.document_data")
+    .from(&format!("{} C", composite_group.lookup_table_name()))
+    .inner_join("documents D ON C.collection_parent_path = D.collection_parent_path and C.collection_id = D.collection_id and C.document_id = D.document_id");
+  let mut args: Vec<Box<dyn ToSql + Sync>> = vec![];
+  for parameter in parameters {
+    // The field name is spliced into the sql, so it must be one of the group's columns
+    assert!(composite_group.has_field(&parameter.field_name), "{} is not a field of the composite group", parameter.field_name);
+    let (constraint, values) = field_value_constraint(
+      &format!("C.{}", quote_identifier(&parameter.field_name)), &parameter.op.as_operator(), args.len() + 1, &parameter.parameter);
+    query = query.where_clause(&constraint);
+    args.extend(values.into_iter().map(|x| Box::new(x) as Box<dyn ToSql + Sync>));
+  }
+
+  let (document_id_constraints, document_id_args) = document_id_constraints("D", &query_options.document_id_filters, args.len() + 1);
+  for constraint in document_id_constraints {
+    query = query.where_clause(&constraint);
+  }
+  args.extend(document_id_args.into_iter());
+
+  // Without an explicit order, documents come back in the group's order on its primary field, with
+  // ties broken by path in the same direction. Composite subscriptions number their changes by this
+  // order.
+  let mut query_options = query_options.clone();
+  if query_options.order_by.is_empty() {
+    let descending = composite_group.is_descending(&composite_group.primary_field_name);
+    query_options.order_by.push(QueryOrder {
+      field: OrderField::Field(composite_group.primary_field_name.clone()),
+      descending,
+    });
+    query_options.order_by.push(QueryOrder { field: OrderField::FullPath, descending });
+  }
+  let order_by_and_limit = order_by_and_limit_clause(&query_options, |order_field_name| {
+    assert!(composite_group.has_field(order_field_name),
+            "A composite query can only be ordered by the fields of its composite group or document id");
+    format!("C.{}", quote_identifier(order_field_name))
+  });
+  (format!("{}{}", query.as_string(), order_by_and_limit), args)
+}
+
+pub(super) fn subscribe_to_composite_query(
+  transaction: &mut Transaction,
+  client_id: &str,
+  user_id: &UserId,
+  sorted_parameters: &[QueryParameter],
+  composite_group: &CompositeFieldGroup,
+  resume_point: &Option<ResumePoint>)
+  -> Subscription
+{
+  if let User(user_id) = user_id {
+    assert!(operation_is_allowed(user_id, &Operation::List,
+                                 &composite_group.collection_parent_path,
+                                 &composite_group.collection_id, &None));
+  }
+
+  let subscription_id: String = Uuid::new_v4().as_simple().to_string();
+  register_client_subscription(transaction, &subscription_id, client_id, user_id, &composite_group.collection_parent_path,
+                               &composite_group.collection_id, &None, &Some(composite_group.group_id.clone()));
+
+  let mut primary_less_than_param = None;
+  let mut primary_greater_than_parameter = None;
+  let mut primary_type_bound_value = None;
+  let mut primary_excluded_parameters = vec![];
+  let mut secondary_columns = vec![];
+  let mut secondary_parameters = vec![];
+
+  for parameter in sorted_parameters {
+    if parameter.is_primary {
+      let cross_type = matches!(parameter.op, Op::CrossTypeLt | Op::CrossTypeLte | Op::CrossTypeGt | Op::CrossTypeGte);
+      if !cross_type && parameter.op != Op::Neq {
+        primary_type_bound_value = Some(parameter.parameter.clone());
+      }
+      match parameter.op {
+        Op::Lte | Op::CrossTypeLte => primary_less_than_param = Some(parameter.parameter.clone()),
+        Op::Gte | Op::CrossTypeGte => primary_greater_than_parameter = Some(parameter.parameter.clone()),
+        Op::Lt | Op::CrossTypeLt => {
+          primary_less_than_param = Some(parameter.parameter.clone());
+          primary_excluded_parameters.push(parameter.parameter.clone());
+        }
+        Op::Gt | Op::CrossTypeGt => {
+          primary_greater_than_parameter = Some(parameter.parameter.clone());
+          primary_excluded_parameters.push(parameter.parameter.clone());
+        }
+        Op::Eq => {
+          primary_less_than_param = Some(parameter.parameter.clone());
+          primary_greater_than_parameter = Some(parameter.parameter.clone());
+        }
+        Op::Neq => primary_excluded_parameters.push(parameter.parameter.clone()),
+        op => panic!("The {:?} operator is not supported on the primary field of a composite subscription", op),
+      }
+    } else {
+      match parameter.op {
+        Op::Eq => secondary_columns.push(quote_identifier(&parameter.field_name)),
+        Op::StartsWith => secondary_columns.push(CompositeFieldGroup::prefix_column_name(&parameter.field_name)),
+        op => panic!("The {:?} operator is not supported on the secondary fields of a composite group", op),
+      }
+      secondary_parameters.push(parameter.parameter.clone());
+    }
+  }
+
+  // An open ended range is closed at the ends of the query value's type, unless the range was
+  // explicitly made cross type
+  let (primary_greater_than_parameter, primary_less_than_param) = if let Some(type_bound_value) = primary_type_bound_value {
+    let ((lower_bound, _), (upper_bound, upper_operator)) = type_range_bounds(&type_bound_value);
+    if primary_less_than_param.is_none() && upper_operator == "<" {
+      primary_excluded_parameters.push(upper_bound.clone());
+    }
+    (primary_greater_than_parameter.unwrap_or(lower_bound), primary_less_than_param.unwrap_or(upper_bound))
+  } else {
+    (primary_greater_than_parameter.unwrap_or(field_value::min()), primary_less_than_param.unwrap_or(field_value::max()))
+  };
+
+  let mut column_string = format!("(subscription_id, {}, {}", composite_group.min_column_name(), composite_group.max_column_name());
+  let mut row_string = "($1, $2, $3".to_owned();
+  for (i, secondary_column) in secondary_columns.iter().enumerate() {
+    column_string.push_str(&format!(", {}", secondary_column));
+    row_string.push_str(&format!(", ${}", i + 4));
+  }
+  column_string.push(')');
+  row_string.push(')');
+  let included_query_string = format!("insert into {} {} values {}", composite_group.included_subscription_table_name(), column_string, row_string);
+  let mut included_args: Vec<&(dyn ToSql + Sync)> = vec![&subscription_id, &primary_greater_than_parameter, &primary_less_than_param];
+  for secondary_parameter in secondary_parameters.iter() {
+    included_args.push(secondary_parameter);
+  }
+
+  transaction.execute(&included_query_string,
+                      &included_args).unwrap();
+  let excluded_query_string = format!("insert into {} (subscription_id, {}) values ($1, $2)",
+                                      composite_group.excluded_subscription_table_name(), composite_group.excluded_column_name());
+  for excluded_value in primary_excluded_parameters {
+    transaction.execute(&excluded_query_string, &[&subscription_id, &excluded_value]).unwrap();
+  }
+
+  let snapshot = composite_query(transaction, &UserId::Admin, sorted_parameters, composite_group, &QueryOptions::default(), &None);
+  let snapshot = readable_documents(user_id, snapshot);
+  for document in &snapshot {
+    add_document_to_composite_subscription_result(transaction, &subscription_id, document, composite_group);
+  }
+  write_initial_updates(transaction, client_id, &subscription_id, resume_point, &composite_group.collection_parent_path,
+                        &composite_group.collection_id, &None, &snapshot, true)
+}
+
+pub(super) fn stream_simple_query<'a, 'b>(
+  transaction: &'a mut Transaction<'b>,
+  user_id: &UserId,
+  collection_parent_path: &Option<String>,
+  collection_id: &str,
+  field_name: &str,
+  filters: &[(Op, field_value)],
+  query_options: &QueryOptions,
+  field_mask: &Option<Vec<String>>,
+  batch_size: i32)
+  -> DocumentStream<'a, 'b>
+{
+  if let User(user_id) = user_id {
+    assert!(operation_is_allowed(user_id, &Operation::List,
+                                 &collection_parent_path,
+                                 collection_id, &None));
+  }
+  assert_field_is_indexed(transaction, collection_parent_path, collection_id, field_name);
+
+  let (query_string, args) = simple_query_statement(collection_parent_path, collection_id, field_name, filters, query_options);
+  let args: Vec<&(dyn ToSql + Sync)> = args.iter().map(|x| x.as_ref()).collect();
+  bind_document_stream(transaction, &query_string, &args, batch_size, field_mask)
+}
+
+pub(super) fn stream_composite_query<'a, 'b>(
+  transaction: &'a mut Transaction<'b>,
+  user_id: &UserId,
+  parameters: &[QueryParameter],
+  composite_group: &CompositeFieldGroup,
+  query_options: &QueryOptions,
+  field_mask: &Option<Vec<String>>,
+  batch_size: i32)
+  -> DocumentStream<'a, 'b>
+{
+  if let User(user_id) = user_id {
+    assert!(operation_is_allowed(user_id, &Operation::List,
+                                 &composite_group.collection_parent_path,
+                                 &composite_group.collection_id, &None));
+  }
+  assert!(composite_group_is_ready(transaction, &composite_group.group_id),
+          "The composite group {} is still being built", composite_group.group_id);
+
+  let (query_string, args) = composite_query_statement(parameters, composite_group, query_options);
+  let args: Vec<&(dyn ToSql + Sync)> = args.iter().map(|x| x.as_ref()).collect();
+  bind_document_stream(transaction, &query_string, &args, batch_size, field_mask)
+}
+
+pub(super) fn explain_simple_query(
+  transaction: &mut Transaction,
+  user_id: &UserId,
+  collection_parent_path: &Option<String>,
+  collection_id: &str,
+  field_name: &str,
+  filters: &[(Op, field_value)],
+  query_options: &QueryOptions,
+) -> QueryExplanation {
+  if let User(user_id) = user_id {
+    assert!(operation_is_allowed(user_id, &Operation::List,
+                                 &collection_parent_path,
+                                 collection_id, &None));
+  }
+  assert_field_is_indexed(transaction, collection_parent_path, collection_id, field_name);
+
+  let (query_string, args) = simple_query_statement(collection_parent_path, collection_id, field_name, filters, query_options);
+  let args: Vec<&(dyn ToSql + Sync)> = args.iter().map(|x| x.as_ref()).collect();
+  explain(transaction, &query_string, &args, SIMPLE_QUERY_INDEX_NAME)
+}
+
+pub(super) fn explain_composite_query(
+  transaction: &mut Transaction,
+  user_id: &UserId,
+  parameters: &[QueryParameter],
+  composite_group: &CompositeFieldGroup,
+  query_options: &QueryOptions,
+) -> QueryExplanation {
+  if let User(user_id) = user_id {
+    assert!(operation_is_allowed(user_id, &Operation::List,
+                                 &composite_group.collection_parent_path,
+                                 &composite_group.collection_id, &None));
+  }
+
+  assert!(composite_group_is_ready(transaction, &composite_group.group_id),
+          "The composite group {} is still being built", composite_group.group_id);
+
+  let (query_string, args) = composite_query_statement(parameters, composite_group, query_options);
+  let args: Vec<&(dyn ToSql + Sync)> = args.iter().map(|x| x.as_ref()).collect();
+  explain(transaction, &query_string, &args, &composite_group.lookup_index_name())
+}
@@ -0,0 +1,73 @@
+use postgres::Transaction;
+use postgres::types::ToSql;
+
+use crate::composite_query::{composite_subscription_match_statement, CompositeFieldGroup};
+use crate::protos::document_protos::Document;
+use crate::simple_query::{not_exists_subscription_match_statements, simple_query_subscription_match_statements};
+use crate::sql_types::field_value;
+use crate::utils::field_value_proto_to_sql;
+
+pub(crate) const SIMPLE_QUERY_INDEX_NAME: &str = "simple_query_idx";
+const SIMPLE_QUERY_SUBSCRIPTION_INDEX_NAME: &str = "simple_query_collection_subscription_idx";
+
+#[derive(Debug, Clone)]
+pub struct QueryExplanation {
+  pub sql: String,
+  pub plan: Vec<String>,
+  pub index_name: String,
+  pub index_used: bool,
+}
+
+// Runs EXPLAIN on the generated sql with the same arguments the query would be run with and
+// checks whether the plan references the index we expect the query to use
+pub(crate) fn explain(
+  transaction: &mut Transaction,
+  query_string: &str,
+  args: &[&(dyn ToSql + Sync)],
+  index_name: &str,
+) -> QueryExplanation {
+  let plan: Vec<String> = transaction.query(&format!("EXPLAIN {}", query_string), args)
+    .unwrap().into_iter()
+    .map(|row| row.get(0))
+    .collect();
+  let index_used = plan.iter().any(|line| line.contains(index_name));
+
+  QueryExplanation {
+    sql: query_string.to_owned(),
+    plan,
+    index_name: index_name.to_owned(),
+    index_used,
+  }
+}
+
+// Explains every query run by get_matching_simple_query_subscriptions for the document
+pub fn explain_simple_query_subscription_matching(
+  transaction: &mut Transaction,
+  collection_parent_path: &str,
+  collection_id: &str,
+  document: &Document,
+) -> Vec<QueryExplanation> {
+  let mut explanations = vec![];
+  for (field_name, field_value) in document.fields.iter() {
+    let sql_field_value = field_value_proto_to_sql(field_value);
+    for (query_string, args) in simple_query_subscription_match_statements(collection_parent_path, collection_id, field_name, &sql_field_value) {
+      let args: Vec<&(dyn ToSql + Sync)> = args.iter().map(|x| x.as_ref()).collect();
+      explanations.push(explain(transaction, &query_string, &args, SIMPLE_QUERY_SUBSCRIPTION_INDEX_NAME));
+    }
+  }
+  for (query_string, args) in not_exists_subscription_match_statements(collection_parent_path, collection_id, document) {
+    let args: Vec<&(dyn ToSql + Sync)> = args.iter().map(|x| x.as_ref()).collect();
+    explanations.push(explain(transaction, &query_string, &args, SIMPLE_QUERY_SUBSCRIPTION_INDEX_NAME));
+  }
+  explanations
+}
+
+pub fn explain_composite_subscription_matching(
+  transaction: &mut Transaction,
+  document: &Document,
+  composite_group: &CompositeFieldGroup,
+) -> QueryExplanation {
+  let (query_string, args) = composite_subscription_match_statement(document, composite_group);
+  let args: Vec<&(dyn ToSql + Sync)> = args.iter().map(|x| x.as_ref()).collect();
+  explain(transaction, &query_string, &args, &composite_group.included_subscription_index_name())
+}
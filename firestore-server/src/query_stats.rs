@@ -0,0 +1,238 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{RecvTimeoutError, sync_channel, SyncSender};
+use std::sync::OnceLock;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+use postgres::{Client, NoTls, Transaction};
+
+use crate::composite_groups::get_composite_groups;
+use crate::composite_query::CompositeFieldGroup;
+use crate::index_config::{CompositeGroupConfig, group_matches_config};
+use crate::utils::{EXISTS_OPERATOR, NOT_EXISTS_OPERATOR, STARTS_WITH_OPERATOR};
+
+// Stats are handed to a writer thread with its own connection, so recording them never slows
+// down or fails a query, and they are kept even when the query's transaction rolls back, which is
+// exactly what happens when no composite group covers a query
+static QUERY_STATS_SENDER: OnceLock<SyncSender<QueryStat>> = OnceLock::new();
+// Stats recorded while the queue is full are dropped, the writer thread periodically reports how
+// many were
+const QUERY_STATS_QUEUE_SIZE: usize = 10000;
+const QUERY_STATS_BATCH_SIZE: usize = 100;
+const DROPPED_QUERY_STATS_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+static DROPPED_QUERY_STATS: AtomicU64 = AtomicU64::new(0);
+
+struct QueryStat {
+  shape: QueryShape,
+  is_subscription: bool,
+  composite_group_id: Option<String>,
+  failed: bool,
+  duration_ms: Option<f64>,
+  recorded_at: SystemTime,
+}
+
+// Query shapes are only recorded after this is called
+pub fn start_recording_query_stats(connection_string: &str) -> JoinHandle<()> {
+  let mut client = Client::connect(connection_string, NoTls).unwrap();
+  let (sender, receiver) = sync_channel(QUERY_STATS_QUEUE_SIZE);
+  assert!(QUERY_STATS_SENDER.set(sender).is_ok(), "Query stats are already being recorded");
+  thread::spawn(move || {
+    let mut last_reported_at = Instant::now();
+    loop {
+      // Each batch is whatever queued up while the previous one was being written
+      match receiver.recv_timeout(DROPPED_QUERY_STATS_REPORT_INTERVAL) {
+        Ok(stat) => {
+          let mut batch = vec![stat];
+          while batch.len() < QUERY_STATS_BATCH_SIZE {
+            match receiver.try_recv() {
+              Ok(stat) => batch.push(stat),
+              Err(_) => break,
+            }
+          }
+          if let Err(error) = write_query_stats(&mut client, &batch) {
+            eprintln!("Failed to record {} query stats: {}", batch.len(), error);
+          }
+        }
+        Err(RecvTimeoutError::Timeout) => {}
+        Err(RecvTimeoutError::Disconnected) => return,
+      }
+
+      if last_reported_at.elapsed() >= DROPPED_QUERY_STATS_REPORT_INTERVAL {
+        let dropped_count = DROPPED_QUERY_STATS.swap(0, Ordering::Relaxed);
+        if dropped_count > 0 {
+          eprintln!("Dropped {} query stats because the queue was full", dropped_count);
+        }
+        last_reported_at = Instant::now();
+      }
+    }
+  })
+}
+
+fn write_query_stats(client: &mut Client, batch: &[QueryStat]) -> Result<(), postgres::Error> {
+  let mut transaction = client.transaction()?;
+  let statement = transaction.prepare("insert into query_stats values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)")?;
+  for stat in batch {
+    // Filters on a single field never need a composite group, however many there are
+    let needs_composite_group = stat.shape.field_names.iter().collect::<HashSet<_>>().len() > 1;
+    transaction.execute(
+      &statement,
+      &[&stat.shape.collection_parent_path, &stat.shape.collection_id, &stat.shape.field_names, &stat.shape.operators,
+        &stat.is_subscription, &needs_composite_group, &stat.composite_group_id, &stat.failed, &stat.duration_ms,
+        &stat.recorded_at])?;
+  }
+  transaction.commit()
+}
+
+// The filters of a query without their values. Filters are sorted so that the same filters in a
+// different order are the same shape.
+#[derive(Debug, Clone)]
+pub struct QueryShape {
+  pub collection_parent_path: Option<String>,
+  pub collection_id: String,
+  pub field_names: Vec<String>,
+  pub operators: Vec<String>,
+}
+
+impl QueryShape {
+  pub fn new(collection_parent_path: &Option<String>, collection_id: &str, filters: &[(String, String)]) -> QueryShape {
+    let mut filters = filters.to_vec();
+    filters.sort();
+    QueryShape {
+      collection_parent_path: collection_parent_path.clone(),
+      collection_id: collection_id.to_owned(),
+      field_names: filters.iter().map(|x| x.0.clone()).collect(),
+      operators: filters.iter().map(|x| x.1.clone()).collect(),
+    }
+  }
+}
+
+pub fn record_query_shape(
+  shape: &QueryShape,
+  is_subscription: bool,
+  composite_group_id: &Option<String>,
+  failed: bool,
+  duration: Option<Duration>,
+) {
+  if let Some(sender) = QUERY_STATS_SENDER.get() {
+    let stat = QueryStat {
+      shape: shape.clone(),
+      is_subscription,
+      composite_group_id: composite_group_id.clone(),
+      failed,
+      duration_ms: duration.map(|x| x.as_secs_f64() * 1000.0),
+      recorded_at: SystemTime::now(),
+    };
+    if sender.try_send(stat).is_err() {
+      DROPPED_QUERY_STATS.fetch_add(1, Ordering::Relaxed);
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompositeGroupSuggestion {
+  pub group: CompositeGroupConfig,
+  pub query_count: i64,
+  pub failure_count: i64,
+  // The slowest average duration of the shapes the group would cover
+  pub average_duration_ms: Option<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexAdvisorReport {
+  pub suggested_groups: Vec<CompositeGroupSuggestion>,
+  // Every group adds to the cost of each write to its collection
+  pub unused_groups: Vec<CompositeFieldGroup>,
+}
+
+// Suggests a composite group for every recorded shape that failed at least min_failures times or
+// took at least slow_query_ms on average, and lists the groups no recorded query used
+pub fn index_advisor_report(transaction: &mut Transaction, min_failures: i64, slow_query_ms: f64) -> IndexAdvisorReport {
+  let live_groups = get_composite_groups(transaction);
+
+  let rows = transaction.query(
+    "select collection_parent_path, collection_id, field_names, operators,
+            count(*), count(*) filter (where failed), avg(duration_ms)
+     from query_stats
+     where needs_composite_group
+     group by collection_parent_path, collection_id, field_names, operators
+     having count(*) filter (where failed) >= $1 or avg(duration_ms) >= $2",
+    &[&min_failures, &slow_query_ms]).unwrap();
+
+  let mut suggested_groups: Vec<CompositeGroupSuggestion> = vec![];
+  for row in rows {
+    let shape = QueryShape {
+      collection_parent_path: row.get(0),
+      collection_id: row.get(1),
+      field_names: row.get(2),
+      operators: row.get(3),
+    };
+    let group = match suggest_composite_group(&shape) {
+      Some(group) => group,
+      None => continue,
+    };
+    // The shape may have failed while its group was still being built
+    if live_groups.iter().any(|live_group| group_matches_config(live_group, &group)) {
+      continue;
+    }
+    let query_count: i64 = row.get(4);
+    let failure_count: i64 = row.get(5);
+    let average_duration_ms: Option<f64> = row.get(6);
+
+    // Shapes with the same fields but different operators can share a group
+    if let Some(suggestion) = suggested_groups.iter_mut().find(|x| x.group == group) {
+      suggestion.query_count += query_count;
+      suggestion.failure_count += failure_count;
+      suggestion.average_duration_ms = match (suggestion.average_duration_ms, average_duration_ms) {
+        (Some(x), Some(y)) => Some(x.max(y)),
+        (x, y) => x.or(y),
+      };
+      continue;
+    }
+    suggested_groups.push(CompositeGroupSuggestion { group, query_count, failure_count, average_duration_ms });
+  }
+
+  let used_group_ids: HashSet<String> = transaction.query(
+    "select distinct composite_group_id from query_stats where composite_group_id IS NOT NULL", &[],
+  ).unwrap().into_iter()
+    .map(|row| row.get(0))
+    .collect();
+  let unused_groups = live_groups.into_iter()
+    .filter(|group| !used_group_ids.contains(&group.group_id))
+    .collect();
+
+  IndexAdvisorReport { suggested_groups, unused_groups }
+}
+
+// The field with an inequality filter has to be the primary field, composite groups only support
+// equality and prefix filters on their secondary fields
+fn suggest_composite_group(shape: &QueryShape) -> Option<CompositeGroupConfig> {
+  if shape.operators.iter().any(|x| x == EXISTS_OPERATOR || x == NOT_EXISTS_OPERATOR) {
+    return None;
+  }
+
+  let mut field_names: Vec<String> = shape.field_names.clone();
+  field_names.dedup();
+  let range_field_names: HashSet<&String> = shape.field_names.iter().zip(shape.operators.iter())
+    .filter(|(_, operator)| *operator != "=" && *operator != STARTS_WITH_OPERATOR)
+    .map(|(field_name, _)| field_name)
+    .collect();
+  if range_field_names.len() > 1 {
+    return None;
+  }
+  let primary_field = range_field_names.into_iter().next().unwrap_or(&field_names[0]).clone();
+  let secondary_fields: Vec<String> = field_names.into_iter().filter(|x| *x != primary_field).collect();
+  // A query on a single field is answered without a composite group
+  if secondary_fields.is_empty() {
+    return None;
+  }
+
+  Some(CompositeGroupConfig {
+    collection_parent_path: shape.collection_parent_path.clone(),
+    collection_id: shape.collection_id.clone(),
+    primary_field,
+    secondary_fields,
+    descending_fields: vec![],
+  })
+}
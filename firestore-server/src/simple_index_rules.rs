@@ -0,0 +1,233 @@
+use itertools::Itertools;
+use postgres::Transaction;
+use postgres::types::ToSql;
+
+use crate::basic_read::decode_document_rows;
+use crate::utils::field_value_proto_to_sql;
+
+const REINDEX_BATCH_SIZE: i64 = 500;
+
+// Decides whether fields are written to the simple query lookup table. Every field is indexed by
+// default. A rule without a field name applies to every field of the collection, eg. to make a
+// collection opt in, and a rule without a collection parent path applies to every collection with
+// the collection id. When several rules apply, the one naming the field wins, and then the one
+// naming the collection parent path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimpleIndexRule {
+  pub collection_parent_path: Option<String>,
+  pub collection_id: String,
+  pub field_name: Option<String>,
+  pub is_indexed: bool,
+}
+
+impl SimpleIndexRule {
+  // A field name of None stands for the fields no rule names
+  fn applies_to(&self, collection_parent_path: &Option<String>, field_name: Option<&str>) -> bool {
+    (self.collection_parent_path.is_none() || self.collection_parent_path == *collection_parent_path)
+      && self.field_name.as_ref().map_or(true, |x| Some(x.as_str()) == field_name)
+  }
+
+  fn specificity(&self) -> u8 {
+    (if self.field_name.is_some() { 2 } else { 0 }) + (if self.collection_parent_path.is_some() { 1 } else { 0 })
+  }
+}
+
+// Adds or replaces a rule, and reindexes the documents it covers
+pub fn set_simple_index_rule(
+  transaction: &mut Transaction,
+  collection_parent_path: &Option<String>,
+  collection_id: &str,
+  field_name: &Option<String>,
+  is_indexed: bool,
+) {
+  transaction.execute(
+    "insert into simple_index_rules values ($1, $2, $3, $4)
+     ON CONFLICT (collection_id, field_name, collection_parent_path) DO UPDATE SET is_indexed = $4",
+    &[&collection_parent_path, &collection_id, &field_name, &is_indexed]).unwrap();
+  reindex_simple_query_table(transaction, collection_parent_path, collection_id, field_name);
+}
+
+pub fn remove_simple_index_rule(
+  transaction: &mut Transaction,
+  collection_parent_path: &Option<String>,
+  collection_id: &str,
+  field_name: &Option<String>,
+) {
+  transaction.execute(
+    "delete from simple_index_rules
+     where collection_parent_path IS NOT DISTINCT FROM $1 and collection_id = $2 and field_name IS NOT DISTINCT FROM $3",
+    &[&collection_parent_path, &collection_id, &field_name]).unwrap();
+  reindex_simple_query_table(transaction, collection_parent_path, collection_id, field_name);
+}
+
+pub fn get_simple_index_rules(transaction: &mut Transaction) -> Vec<SimpleIndexRule> {
+  transaction.query("select collection_parent_path, collection_id, field_name, is_indexed from simple_index_rules", &[])
+    .unwrap().into_iter()
+    .map(|row| SimpleIndexRule {
+      collection_parent_path: row.get(0),
+      collection_id: row.get(1),
+      field_name: row.get(2),
+      is_indexed: row.get(3),
+    })
+    .collect()
+}
+
+// Returns the rules that can apply to documents in the collection. A collection parent path of
+// None returns the rules for every collection with the collection id.
+pub fn get_simple_index_rules_for_collection(
+  transaction: &mut Transaction,
+  collection_parent_path: &Option<String>,
+  collection_id: &str,
+) -> Vec<SimpleIndexRule> {
+  transaction.query(
+    "select collection_parent_path, collection_id, field_name, is_indexed from simple_index_rules
+     where collection_id = $2 and ($1::TEXT IS NULL or collection_parent_path IS NULL or collection_parent_path = $1)",
+    &[&collection_parent_path, &collection_id]).unwrap().into_iter()
+    .map(|row| SimpleIndexRule {
+      collection_parent_path: row.get(0),
+      collection_id: row.get(1),
+      field_name: row.get(2),
+      is_indexed: row.get(3),
+    })
+    .collect()
+}
+
+pub fn field_is_indexed(rules: &[SimpleIndexRule], collection_parent_path: &str, field_name: &str) -> bool {
+  resolve_is_indexed(rules, &Some(collection_parent_path.to_owned()), Some(field_name))
+}
+
+// Simple queries and subscriptions read the lookup table, so a field that isn't indexed would
+// silently match nothing. A collection group query needs the field indexed in every collection.
+pub fn assert_field_is_indexed(
+  transaction: &mut Transaction,
+  collection_parent_path: &Option<String>,
+  collection_id: &str,
+  field_name: &str,
+) {
+  let rules = get_simple_index_rules_for_collection(transaction, collection_parent_path, collection_id);
+  let mut scopes = vec![collection_parent_path.clone()];
+  if collection_parent_path.is_none() {
+    scopes.extend(rules.iter().filter(|x| x.collection_parent_path.is_some()).map(|x| x.collection_parent_path.clone()));
+  }
+  for scope in scopes {
+    assert!(resolve_is_indexed(&rules, &scope, Some(field_name)),
+            "The field {} is exempted from simple indexing in {}{}, so it can't be queried on its own",
+            field_name, scope.as_deref().unwrap_or(""), collection_id);
+  }
+}
+
+fn resolve_is_indexed(rules: &[SimpleIndexRule], collection_parent_path: &Option<String>, field_name: Option<&str>) -> bool {
+  rules.iter()
+    .filter(|rule| rule.applies_to(collection_parent_path, field_name))
+    .max_by_key(|rule| rule.specificity())
+    .map_or(true, |rule| rule.is_indexed)
+}
+
+// Brings the lookup table in line with the rules after the rule for the field, or for every field
+// when the field name is None, changed. Rules naming a collection or a field carve it out of a
+// broader rule, so each combination of them is resolved separately, with None standing for the
+// collections and fields no rule names. Fields that are no longer indexed are deleted in a single
+// statement. Documents are stored encoded, so indexing a field decodes them in batches.
+fn reindex_simple_query_table(
+  transaction: &mut Transaction,
+  collection_parent_path: &Option<String>,
+  collection_id: &str,
+  field_name: &Option<String>,
+) {
+  let rules = get_simple_index_rules_for_collection(transaction, collection_parent_path, collection_id);
+  let mut named_collection_parent_paths = vec![];
+  let mut collection_parent_path_scopes = vec![collection_parent_path.clone()];
+  if collection_parent_path.is_none() {
+    named_collection_parent_paths = rules.iter().filter_map(|x| x.collection_parent_path.clone()).unique().collect();
+    collection_parent_path_scopes.extend(named_collection_parent_paths.iter().map(|x| Some(x.clone())));
+  }
+  let mut named_field_names = vec![];
+  let mut field_name_scopes = vec![field_name.clone()];
+  if field_name.is_none() {
+    named_field_names = rules.iter().filter_map(|x| x.field_name.clone()).unique().collect();
+    field_name_scopes.extend(named_field_names.iter().map(|x| Some(x.clone())));
+  }
+
+  for collection_parent_path_scope in &collection_parent_path_scopes {
+    let mut collection_constraint = "collection_id = $1".to_owned();
+    let mut args: Vec<Box<dyn ToSql + Sync>> = vec![Box::new(collection_id.to_owned())];
+    match collection_parent_path_scope {
+      Some(scope) => {
+        collection_constraint.push_str(" and collection_parent_path = $2");
+        args.push(Box::new(scope.clone()));
+      }
+      None => {
+        collection_constraint.push_str(" and collection_parent_path <> ALL($2)");
+        args.push(Box::new(named_collection_parent_paths.clone()));
+      }
+    }
+
+    for field_name_scope in &field_name_scopes {
+      if resolve_is_indexed(&rules, collection_parent_path_scope, field_name_scope.as_deref()) {
+        index_documents(transaction, &collection_constraint, &args, |name| match field_name_scope {
+          Some(scope) => name == scope.as_str(),
+          None => !named_field_names.iter().any(|x| x == name),
+        });
+        continue;
+      }
+      let mut args: Vec<&(dyn ToSql + Sync)> = args.iter().map(|x| x.as_ref()).collect();
+      let field_constraint = match field_name_scope {
+        Some(scope) => {
+          args.push(scope);
+          "field_name = $3"
+        }
+        None => {
+          args.push(&named_field_names);
+          "field_name <> ALL($3)"
+        }
+      };
+      transaction.execute(
+        &format!("delete from simple_query_lookup where {} and {}", collection_constraint, field_constraint),
+        &args).unwrap();
+    }
+  }
+}
+
+// Adds the missing lookup rows of the selected fields for the documents matching the constraint,
+// which uses the first two arguments
+fn index_documents<F: Fn(&str) -> bool>(
+  transaction: &mut Transaction,
+  collection_constraint: &str,
+  args: &[Box<dyn ToSql + Sync>],
+  is_selected_field: F,
+) {
+  let query_string = format!(
+    "select collection_parent_path, collection_id, document_id, document_data from documents
+     where {} and (collection_parent_path, document_id) > ($3, $4)
+     order by collection_parent_path, document_id limit $5",
+    collection_constraint);
+  let mut last_collection_parent_path = String::new();
+  let mut last_document_id = String::new();
+  loop {
+    let mut batch_args: Vec<&(dyn ToSql + Sync)> = args.iter().map(|x| x.as_ref()).collect();
+    batch_args.extend([&last_collection_parent_path as &(dyn ToSql + Sync), &last_document_id, &REINDEX_BATCH_SIZE]);
+    let rows = transaction.query(&query_string, &batch_args).unwrap();
+    let documents = decode_document_rows(&rows, &None);
+    for (row, document) in rows.iter().zip(documents.iter()) {
+      let collection_parent_path: String = row.get("collection_parent_path");
+      let collection_id: String = row.get("collection_id");
+      let document_id: String = row.get("document_id");
+      for (field_name, field_value) in document.fields.iter() {
+        if !is_selected_field(field_name) {
+          continue;
+        }
+        let field_value = field_value_proto_to_sql(field_value);
+        transaction.execute(
+          "insert into simple_query_lookup values ($1, $2, $3, $4, $5) ON CONFLICT DO NOTHING",
+          &[&collection_parent_path, &collection_id, &document_id, &field_name, &field_value]).unwrap();
+      }
+    }
+    match rows.last() {
+      Some(last_row) if (rows.len() as i64) == REINDEX_BATCH_SIZE => {
+        last_collection_parent_path = last_row.get("collection_parent_path");
+        last_document_id = last_row.get("document_id");
+      }
+      _ => return,
+    }
+  }
+}
@@ -8,75 +8,135 @@ use itertools::Itertools;
 use postgres::{Client, NoTls, Row, Transaction};
 use postgres::types::{ToSql, Type};
 use prost::Message;
-use uuid::Uuid;
 
 use crate::protos::document_protos::Document;
 use crate::protos::document_protos::field_value::Value;
 use crate::protos::document_protos::FieldValue;
-use crate::security_rules::{Operation, operation_is_allowed, UserId};
-use crate::security_rules::UserId::User;
 use crate::sql_types::field_value;
-use crate::utils::{field_value_proto_to_sql, prepare_field_value_constraint};
-use crate::basic_read::get_document;
-
-// TODO: Add security check when updating subscription data
+use crate::utils::{EXISTS_OPERATOR, field_value_proto_to_sql, field_value_type_names, is_type_operator, NOT_EXISTS_OPERATOR, parse_range_operator, STARTS_WITH_OPERATOR, stored_prefix_constraint, type_range_bounds};
+use crate::simple_index_rules::{field_is_indexed, get_simple_index_rules_for_collection};
+
+// Each subscription operator paired with the operator used to compare the stored subscription
+// value against a document's field value
+pub const SUBSCRIPTION_OPERATOR_PAIRS: [(&str, &str); 10] = [
+  ("<", ">"), ("<=", ">="), ("=", "="), ("!=", "!="), (">", "<"), (">=", "<="),
+  ("cross-type:<", ">"), ("cross-type:<=", ">="), ("cross-type:>", "<"), ("cross-type:>=", "<="),
+];
+
+// Returns the constraint keeping a type bounded range subscription's stored value in the same
+// type as the document's field value
+fn subscription_value_bound(subscription_operator: &str, field_value: &field_value) -> Option<(&'static str, field_value)> {
+  let (comparison, cross_type) = parse_range_operator(subscription_operator);
+  if cross_type {
+    return None;
+  }
+  let ((lower_bound, lower_operator), (upper_bound, upper_operator)) = type_range_bounds(field_value);
+  match comparison {
+    ">" | ">=" => Some((lower_operator, lower_bound)),
+    "<" | "<=" => Some((upper_operator, upper_bound)),
+    _ => None,
+  }
+}
 
-pub fn simple_query(
-  transaction: &mut Transaction,
-  user_id: &UserId,
-  collection_parent_path: &Option<String>,
+fn subscription_match_statement(
+  collection_parent_path: Option<&str>,
   collection_id: &str,
   field_name: &str,
   field_operator: &str,
-  field_value: &field_value,
-) -> Vec<Document> {
-  if let User(user_id) = user_id {
-    assert!(operation_is_allowed(user_id, &Operation::List,
-                                 &collection_parent_path,
-                                 collection_id, &None));
-  }
-
-  let query_result;
+) -> (String, Vec<Box<dyn ToSql + Sync>>) {
+  let mut args: Vec<Box<dyn ToSql + Sync>> = vec![];
+  let mut query_string = "select subscription_id from simple_query_subscriptions where ".to_owned();
   if let Some(collection_parent_path) = collection_parent_path {
-    let query_string = format!("SELECT collection_parent_path, collection_id, document_id from simple_query_lookup where collection_parent_path = $1 and collection_id = $2 and field_name = $3 and field_value {} $4", field_operator);
-    query_result = transaction.query(
-      &query_string,
-      &[&collection_parent_path, &collection_id, &field_name, &field_value])
+    query_string.push_str("collection_parent_path = $1");
+    args.push(Box::new(collection_parent_path.to_owned()));
   } else {
-    let query_string = format!("SELECT collection_parent_path, collection_id, document_id from simple_query_lookup where collection_id = $1 and field_name = $2 and field_value {} $3", field_operator);
-    query_result = transaction.query(
-      &query_string,
-      &[&collection_id, &field_name, &field_value])
+    query_string.push_str("collection_parent_path IS NULL");
   }
-  query_result.unwrap().into_iter()
-    .map(|row| get_document(transaction, user_id,row.get("collection_parent_path"),
-                            row.get("collection_id"), row.get("document_id")).unwrap())
-    .collect()
+  query_string.push_str(&format!(" and collection_id = ${} and field_name = ${} and field_operator = ${}",
+                                 args.len() + 1, args.len() + 2, args.len() + 3));
+  args.push(Box::new(collection_id.to_owned()));
+  args.push(Box::new(field_name.to_owned()));
+  args.push(Box::new(field_operator.to_owned()));
+  (query_string, args)
 }
 
-pub fn get_matching_simple_query_subscriptions(transaction: &mut Transaction, collection_parent_path: &str, collection_id: &str, document: &Document) -> Vec<String> {
-  let operator_pairs = vec![("<", ">"), ("<=", ">="), ("=", "="), ("!=", "!="), (">", "<"), (">=", "<=")];
+// Returns every statement needed to find the simple query subscriptions matching a document's
+// field value, for both collection and collection group subscriptions
+pub fn simple_query_subscription_match_statements(
+  collection_parent_path: &str,
+  collection_id: &str,
+  field_name: &str,
+  field_value: &field_value,
+) -> Vec<(String, Vec<Box<dyn ToSql + Sync>>)> {
+  let mut statements = vec![];
+  for scope_parent_path in [Some(collection_parent_path), None] {
+    for (subscription_operator, reversed_operator) in SUBSCRIPTION_OPERATOR_PAIRS.iter() {
+      let (mut query_string, mut args) = subscription_match_statement(scope_parent_path, collection_id, field_name, subscription_operator);
+      query_string.push_str(&format!(" and field_value {} ${}", reversed_operator, args.len() + 1));
+      args.push(Box::new(field_value.clone()));
+      if let Some((bound_operator, bound)) = subscription_value_bound(subscription_operator, field_value) {
+        query_string.push_str(&format!(" and field_value {} ${}", bound_operator, args.len() + 1));
+        args.push(Box::new(bound));
+      }
+      statements.push((query_string, args));
+    }
+
+    // A starts-with subscription matches when the field value starts with its value
+    let (query_string, args) = subscription_match_statement(scope_parent_path, collection_id, field_name, STARTS_WITH_OPERATOR);
+    if let Some((prefix_constraint, prefix_arg)) = stored_prefix_constraint("field_value", args.len() + 1, field_value) {
+      let mut args = args;
+      args.push(prefix_arg);
+      statements.push((format!("{} and {}", query_string, prefix_constraint), args));
+    }
+
+    // Existence and type subscriptions match on the field alone, so their stored value is ignored
+    statements.push(subscription_match_statement(scope_parent_path, collection_id, field_name, EXISTS_OPERATOR));
+    for type_name in field_value_type_names(field_value) {
+      statements.push(subscription_match_statement(scope_parent_path, collection_id, field_name, &is_type_operator(type_name)));
+    }
+  }
+  statements
+}
+
+// Returns the statements finding the not-exists subscriptions matching a document, which are the
+// subscriptions on any field the document does not have
+pub fn not_exists_subscription_match_statements(
+  collection_parent_path: &str,
+  collection_id: &str,
+  document: &Document,
+) -> Vec<(String, Vec<Box<dyn ToSql + Sync>>)> {
+  let field_names: Vec<String> = document.fields.keys().cloned().collect();
+  let collection_args: Vec<Box<dyn ToSql + Sync>> = vec![
+    Box::new(collection_parent_path.to_owned()), Box::new(collection_id.to_owned()), Box::new(field_names.clone())];
+  let collection_group_args: Vec<Box<dyn ToSql + Sync>> = vec![
+    Box::new(collection_id.to_owned()), Box::new(field_names)];
+  vec![
+    (format!("select subscription_id from simple_query_subscriptions where collection_parent_path = $1 and collection_id = $2 and field_operator = '{}' and field_name <> ALL($3)", NOT_EXISTS_OPERATOR),
+     collection_args),
+    (format!("select subscription_id from simple_query_subscriptions where collection_parent_path IS NULL and collection_id = $1 and field_operator = '{}' and field_name <> ALL($2)", NOT_EXISTS_OPERATOR),
+     collection_group_args),
+  ]
+}
 
+pub fn get_matching_simple_query_subscriptions(transaction: &mut Transaction, collection_parent_path: &str, collection_id: &str, document: &Document) -> Vec<String> {
   let mut matching_subscriptions = vec![];
   for (field_name, field_value) in document.fields.iter() {
     let sql_field_value = field_value_proto_to_sql(field_value);
-    for operator_pair in &operator_pairs {
-      let collection_query = format!("select subscription_id from simple_query_subscriptions where collection_parent_path = $1 and collection_id = $2 and field_name = $3 and field_operator = $4 and field_value {} $5", operator_pair.1);
-      let collection_subscriptions = transaction.query(
-        &collection_query,
-        &[&collection_parent_path, &collection_id, &field_name, &operator_pair.0, &sql_field_value],
-      ).unwrap().into_iter().map(|x| x.get::<usize, String>(0));
-      matching_subscriptions.extend(collection_subscriptions);
-
-      let collection_group_query = format!("select subscription_id from simple_query_subscriptions where collection_parent_path IS NULL and collection_id = $1 and field_name = $2 and field_operator = $3 and field_value {} $4", operator_pair.1);
-      let collection_group_subscriptions = transaction.query(
-        &collection_group_query,
-        &[&collection_id, &field_name, &operator_pair.0, &sql_field_value],
-      ).unwrap().into_iter().map(|x| x.get::<usize, String>(0));
-      matching_subscriptions.extend(collection_group_subscriptions)
+    for (query_string, args) in simple_query_subscription_match_statements(collection_parent_path, collection_id, field_name, &sql_field_value) {
+      let args: Vec<&(dyn ToSql + Sync)> = args.iter().map(|x| x.as_ref()).collect();
+      let subscriptions = transaction.query(&query_string, &args).unwrap()
+        .into_iter().map(|x| x.get::<usize, String>(0));
+      matching_subscriptions.extend(subscriptions);
     }
   }
 
+  for (query_string, args) in not_exists_subscription_match_statements(collection_parent_path, collection_id, document) {
+    let args: Vec<&(dyn ToSql + Sync)> = args.iter().map(|x| x.as_ref()).collect();
+    let subscriptions = transaction.query(&query_string, &args).unwrap()
+      .into_iter().map(|x| x.get::<usize, String>(0));
+    matching_subscriptions.extend(subscriptions);
+  }
+
   matching_subscriptions
 }
 
@@ -88,7 +148,11 @@ pub fn add_document_to_simple_query_table(
   document: &Document,
 )
 {
+  let index_rules = get_simple_index_rules_for_collection(transaction, &Some(collection_parent_path.to_owned()), collection_id);
   for (field_name, field_value) in document.fields.iter() {
+    if !field_is_indexed(&index_rules, collection_parent_path, field_name) {
+      continue;
+    }
     let field_value = field_value_proto_to_sql(&field_value);
     transaction.execute(
       "insert into simple_query_lookup values ($1, $2, $3, $4, $5)",
@@ -108,37 +172,3 @@ pub fn delete_document_from_simple_query_table(
     &[&collection_parent_path, &collection_id, &document_id]).unwrap();
 }
 
-pub fn subscribe_to_simple_query(
-  transaction: &mut Transaction,
-  client_id: &str,
-  user_id: &UserId,
-  collection_parent_path: &Option<String>,
-  collection_id: &str,
-  field_name: &str,
-  field_operator: &str,
-  field_value: &field_value)
-  -> String
-{
-  if let User(user_id) = user_id {
-    assert!(operation_is_allowed(user_id, &Operation::List,
-                                 &collection_parent_path,
-                                 collection_id, &None));
-  }
-
-  let subscription_id: String = Uuid::new_v4().as_simple().to_string();
-  transaction.execute("insert into client_subscriptions values ($1, $2)",
-                      &[&subscription_id, &client_id]).unwrap();
-
-  let collection_parent_path_string: String;
-  if let Some(collection_parent_path) = collection_parent_path {
-    collection_parent_path_string = collection_parent_path.clone();
-  } else {
-    collection_parent_path_string = "NULL".to_owned();
-  }
-
-  transaction.execute("insert into simple_query_subscriptions values ($1, $2, $3, $4, $5, $6)",
-                      &[&collection_parent_path_string, &collection_id, &field_name, &field_operator, &field_value, &subscription_id]).unwrap();
-
-  // Todo: trigger first subscription update?
-  subscription_id
-}
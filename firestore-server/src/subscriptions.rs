@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+use std::thread;
+use std::thread::{JoinHandle, sleep};
+use std::time::Duration;
+
+use postgres::{Client, NoTls, Row, Transaction};
+
+use crate::composite_groups::get_composite_group;
+use crate::composite_query::remove_document_from_composite_subscription_result;
+use crate::protos::document_protos::Document;
+use crate::security_rules::{Operation, operation_is_allowed, UserId};
+use crate::update_queue::{CHANGE_HISTORY_MAX_AGE, ChangeType, prune_document_changes, SubscriptionChange, track_subscription_document};
+
+// Matches how long Firestore keeps the subscriptions of a disconnected client
+pub const DEFAULT_SUBSCRIPTION_TTL_SECONDS: i64 = 30 * 60;
+const SUBSCRIPTION_EXPIRED_REASON: &str = "The client was idle for longer than the subscription's TTL, subscribe again to resume it";
+// A client that stays away for longer than this isn't told why its subscriptions were cancelled,
+// and its ping time is forgotten once it has no subscriptions left
+pub const CLIENT_STATE_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const READ_ACCESS_REVOKED_REASON: &str = "The subscriber is no longer allowed to read what the subscription matches";
+
+// Keeps the subscriber and what it subscribed to, a document when document_id is set and otherwise
+// a collection or collection group, so that the read rules can be checked again as changes are
+// delivered
+pub fn register_client_subscription(
+  transaction: &mut Transaction,
+  subscription_id: &str,
+  client_id: &str,
+  user_id: &UserId,
+  collection_parent_path: &Option<String>,
+  collection_id: &str,
+  document_id: &Option<String>,
+  composite_group_id: &Option<String>,
+) {
+  let (subscriber_id, is_admin) = match user_id {
+    UserId::Admin => (None, true),
+    UserId::User(user_id) => (user_id.clone(), false),
+  };
+  transaction.execute(
+    "insert into client_subscriptions
+       (subscription_id, client_id, composite_group_id, user_id, is_admin, collection_parent_path, collection_id, document_id)
+     values ($1, $2, $3, $4, $5, $6, $7, $8)",
+    &[&subscription_id, &client_id, &composite_group_id, &subscriber_id, &is_admin,
+      &collection_parent_path, &collection_id, &document_id]).unwrap();
+}
+
+// The documents of a subscription's first updates that the subscriber may read
+pub fn readable_documents(user_id: &UserId, documents: Vec<Document>) -> Vec<Document> {
+  match user_id {
+    UserId::Admin => documents,
+    UserId::User(user_id) => documents.into_iter()
+      .filter(|document| {
+        let id = document.id.clone().unwrap();
+        operation_is_allowed(user_id, &Operation::Get, &Some(id.collection_parent_path), &id.collection_id, &Some(id.document_id))
+      })
+      .collect(),
+  }
+}
+
+// The read rules can change after a subscription was checked against them. A subscriber that may
+// no longer read what it subscribed to loses the subscription, and one that may no longer read the
+// document doesn't see it added, and sees a modification as a removal. A removal only reaches a
+// subscriber that was sent the document, so that it doesn't reveal documents it was never allowed
+// to see.
+pub fn apply_read_rules_to_changes(
+  transaction: &mut Transaction,
+  changes: Vec<SubscriptionChange>,
+  collection_parent_path: &str,
+  collection_id: &str,
+  document_id: &str,
+) -> Vec<SubscriptionChange> {
+  let subscription_ids: Vec<String> = changes.iter().map(|x| x.subscription_id.clone()).collect();
+  let subscriptions: HashMap<String, Row> = transaction.query(
+    "select subscription_id, user_id, is_admin, collection_parent_path, collection_id, document_id, composite_group_id
+     from client_subscriptions where subscription_id = ANY($1)",
+    &[&subscription_ids]).unwrap().into_iter()
+    .map(|row| (row.get(0), row))
+    .collect();
+
+  let mut readable_changes = vec![];
+  for change in changes {
+    // The subscription may have been cancelled since the change was matched
+    let row = match subscriptions.get(&change.subscription_id) {
+      Some(row) => row,
+      None => continue,
+    };
+    let is_admin: bool = row.get(2);
+    let is_readable = is_admin || {
+      let user_id: Option<String> = row.get(1);
+      let subscribed_collection_parent_path: Option<String> = row.get(3);
+      let subscribed_collection_id: String = row.get(4);
+      let subscribed_document_id: Option<String> = row.get(5);
+      let operation = if subscribed_document_id.is_some() { Operation::Get } else { Operation::List };
+      if !operation_is_allowed(&user_id, &operation, &subscribed_collection_parent_path, &subscribed_collection_id, &subscribed_document_id) {
+        cancel_subscription(transaction, &change.subscription_id, READ_ACCESS_REVOKED_REASON);
+        continue;
+      }
+      operation_is_allowed(&user_id, &Operation::Get, &Some(collection_parent_path.to_owned()), collection_id, &Some(document_id.to_owned()))
+    };
+
+    let change = if is_readable || change.change_type == ChangeType::Removed {
+      change
+    } else {
+      // Ordered subscriptions forget the document, so that positions only count what the subscriber can see
+      remove_document_from_composite_subscription_result(transaction, &change.subscription_id, collection_parent_path, collection_id, document_id);
+      if change.change_type != ChangeType::Modified {
+        continue;
+      }
+      SubscriptionChange::removed(&change.subscription_id, change.old_index)
+    };
+
+    // The results of a composite subscription only ever held documents that were sent
+    let composite_group_id: Option<String> = row.get(6);
+    if composite_group_id.is_some() {
+      readable_changes.push(change);
+    } else if let Some(change) = track_subscription_document(transaction, change, collection_parent_path, collection_id, document_id) {
+      readable_changes.push(change);
+    }
+  }
+  readable_changes
+}
+
+pub fn unsubscribe(transaction: &mut Transaction, client_id: &str, subscription_id: &str) {
+  let is_owner = transaction.query_opt(
+    "select 1 from client_subscriptions where subscription_id = $1 and client_id = $2",
+    &[&subscription_id, &client_id]).unwrap().is_some();
+  assert!(is_owner, "The subscription {} does not belong to the client", subscription_id);
+  delete_subscription(transaction, subscription_id);
+}
+
+// Removes every subscription of a client that is going away
+pub fn disconnect_client(transaction: &mut Transaction, client_id: &str) {
+  let subscription_ids: Vec<String> = transaction.query(
+    "select subscription_id from client_subscriptions where client_id = $1",
+    &[&client_id],
+  ).unwrap().into_iter()
+    .map(|row| row.get(0))
+    .collect();
+  for subscription_id in subscription_ids {
+    delete_subscription(transaction, &subscription_id);
+  }
+  transaction.execute("delete from subscription_cancellations where client_id = $1", &[&client_id]).unwrap();
+  transaction.execute("delete from client_ping_times where client_id = $1", &[&client_id]).unwrap();
+}
+
+// Ends a subscription on the server's behalf. The client learns why the next time it retrieves
+// its updates, instead of silently no longer receiving any.
+pub fn cancel_subscription(transaction: &mut Transaction, subscription_id: &str, reason: &str) {
+  let client_ids = delete_subscription(transaction, subscription_id);
+  for client_id in client_ids {
+    transaction.execute(
+      "insert into subscription_cancellations values ($1, $2, $3)",
+      &[&subscription_id, &client_id, &reason]).unwrap();
+  }
+}
+
+// Sets how long the subscription outlives its client's last ping. A cheap to reload
+// subscription to a frequently written document wants a short TTL, while a message history that
+// is expensive to reload wants one of weeks. None falls back to the client's TTL.
+pub fn set_subscription_ttl(transaction: &mut Transaction, client_id: &str, subscription_id: &str, ttl: Option<Duration>) {
+  let ttl_seconds = ttl.map(|x| x.as_secs() as i64);
+  let updated_row_count = transaction.execute(
+    "update client_subscriptions set ttl_seconds = $3 where subscription_id = $1 and client_id = $2",
+    &[&subscription_id, &client_id, &ttl_seconds]).unwrap();
+  assert_eq!(updated_row_count, 1, "The subscription {} does not belong to the client", subscription_id);
+}
+
+// Sets the TTL of the client's subscriptions that don't have their own. None falls back to
+// DEFAULT_SUBSCRIPTION_TTL_SECONDS.
+pub fn set_client_ttl(transaction: &mut Transaction, client_id: &str, ttl: Option<Duration>) {
+  let ttl_seconds = ttl.map(|x| x.as_secs() as i64);
+  transaction.execute(
+    "insert into client_ping_times (client_id, ttl_seconds) values ($1, $2)
+     ON CONFLICT (client_id) DO UPDATE SET ttl_seconds = $2",
+    &[&client_id, &ttl_seconds]).unwrap();
+}
+
+// Cancels every subscription whose client hasn't pinged within the subscription's TTL, and
+// returns how many were expired
+pub fn expire_idle_subscriptions(transaction: &mut Transaction) -> usize {
+  let subscription_ids: Vec<String> = transaction.query(
+    "select C.subscription_id from client_subscriptions C
+     left join client_ping_times P on C.client_id = P.client_id
+     where greatest(C.subscribed_at, P.ping_time)
+       + make_interval(secs => coalesce(C.ttl_seconds, P.ttl_seconds, $1)) < now()",
+    &[&DEFAULT_SUBSCRIPTION_TTL_SECONDS]).unwrap().into_iter()
+    .map(|row| row.get(0))
+    .collect();
+  for subscription_id in subscription_ids.iter() {
+    cancel_subscription(transaction, subscription_id, SUBSCRIPTION_EXPIRED_REASON);
+  }
+  subscription_ids.len()
+}
+
+pub fn spawn_subscription_reaper(connection_string: String, interval: Duration) -> JoinHandle<()> {
+  thread::spawn(move || {
+    let mut client = Client::connect(&connection_string, NoTls).unwrap();
+    loop {
+      let mut transaction = client.transaction().unwrap();
+      expire_idle_subscriptions(&mut transaction);
+      prune_client_state(&mut transaction, CLIENT_STATE_MAX_AGE);
+      prune_document_changes(&mut transaction, CHANGE_HISTORY_MAX_AGE);
+      prune_ended_subscriptions(&mut transaction, CHANGE_HISTORY_MAX_AGE);
+      transaction.commit().unwrap();
+      sleep(interval);
+    }
+  })
+}
+
+// Ping times of clients with subscriptions are kept, the reaper expires the subscriptions relative
+// to them
+pub fn prune_client_state(transaction: &mut Transaction, max_age: Duration) {
+  let max_age_seconds = max_age.as_secs_f64();
+  transaction.execute(
+    "delete from subscription_cancellations where cancelled_at < now() - make_interval(secs => $1)",
+    &[&max_age_seconds]).unwrap();
+  transaction.execute(
+    "delete from client_ping_times P where ping_time < now() - make_interval(secs => $1)
+     and NOT EXISTS (select 1 from client_subscriptions C where C.client_id = P.client_id)",
+    &[&max_age_seconds]).unwrap();
+}
+
+// Forgets the subscriptions that ended more than max_age ago, resuming one of them requires a reset
+pub fn prune_ended_subscriptions(transaction: &mut Transaction, max_age: Duration) {
+  let max_age_seconds = max_age.as_secs_f64();
+  transaction.execute(
+    "with pruned as (
+       delete from ended_subscriptions where ended_at < now() - make_interval(secs => $1) returning subscription_id
+     )
+     delete from subscription_documents where subscription_id in (select subscription_id from pruned)",
+    &[&max_age_seconds]).unwrap();
+}
+
+// The subscriptions a reconnecting client has to subscribe to again, with its latest resume token,
+// because they expired while it was away
+pub fn get_expired_subscriptions(transaction: &mut Transaction, client_id: &str) -> Vec<String> {
+  get_subscription_cancellations(transaction, client_id).into_iter()
+    .filter(|(_, reason)| reason == SUBSCRIPTION_EXPIRED_REASON)
+    .map(|(subscription_id, _)| subscription_id)
+    .collect()
+}
+
+pub fn get_subscription_cancellations(transaction: &mut Transaction, client_id: &str) -> Vec<(String, String)> {
+  transaction.query(
+    "select subscription_id, reason from subscription_cancellations where client_id = $1",
+    &[&client_id],
+  ).unwrap().into_iter()
+    .map(|row| (row.get(0), row.get(1)))
+    .collect()
+}
+
+// Called once the client has been told about its cancelled subscriptions. Only the given ones are
+// deleted, so that a cancellation recorded in the meantime is still delivered.
+pub fn acknowledge_subscription_cancellations(transaction: &mut Transaction, client_id: &str, subscription_ids: &[String]) {
+  transaction.execute(
+    "delete from subscription_cancellations where client_id = $1 and subscription_id = ANY($2)",
+    &[&client_id, &subscription_ids]).unwrap();
+}
+
+// Deletes the subscription from the table that matches it against writes and purges its pending
+// updates. The documents it sent are kept until the ended subscription is pruned, along with the
+// documents whose removal the client was never sent, so that the client can resume it. Returns
+// the client the subscription belonged to.
+fn delete_subscription(transaction: &mut Transaction, subscription_id: &str) -> Vec<String> {
+  let rows = transaction.query(
+    "delete from client_subscriptions where subscription_id = $1 returning client_id, composite_group_id",
+    &[&subscription_id]).unwrap();
+
+  transaction.execute("delete from basic_subscriptions where subscription_id = $1", &[&subscription_id]).unwrap();
+  transaction.execute("delete from simple_query_subscriptions where subscription_id = $1", &[&subscription_id]).unwrap();
+  for composite_group_id in rows.iter().filter_map(|row| row.get::<_, Option<String>>(1)) {
+    // The group's tables are already gone if it was dropped
+    if let Some(composite_group) = get_composite_group(transaction, &composite_group_id) {
+      transaction.execute(
+        &format!("delete from {} where subscription_id = $1", composite_group.included_subscription_table_name()),
+        &[&subscription_id]).unwrap();
+      transaction.execute(
+        &format!("delete from {} where subscription_id = $1", composite_group.excluded_subscription_table_name()),
+        &[&subscription_id]).unwrap();
+    }
+  }
+  transaction.execute(
+    "insert into subscription_documents
+     select subscription_id, collection_parent_path, collection_id, document_id from composite_subscription_results
+     where subscription_id = $1
+     UNION
+     select subscription_id, collection_parent_path, collection_id, document_id from update_queues
+     where subscription_id = $1 and change_type = $2
+     ON CONFLICT DO NOTHING",
+    &[&subscription_id, &ChangeType::Removed.as_str()]).unwrap();
+  transaction.execute("delete from composite_subscription_results where subscription_id = $1", &[&subscription_id]).unwrap();
+  transaction.execute("delete from update_queues where subscription_id = $1", &[&subscription_id]).unwrap();
+  for row in rows.iter() {
+    let client_id: String = row.get(0);
+    transaction.execute(
+      "insert into ended_subscriptions (subscription_id, client_id) values ($1, $2) ON CONFLICT DO NOTHING",
+      &[&subscription_id, &client_id]).unwrap();
+  }
+
+  rows.iter().map(|row| row.get(0)).collect()
+}
@@ -1,6 +1,5 @@
 use postgres::Transaction;
 
-use crate::composite_query::CompositeFieldGroup;
 use crate::protos::document_protos::Document;
 use crate::security_rules::UserId;
 use crate::write::{delete_document, write_document};
@@ -8,7 +7,6 @@ use crate::write::{delete_document, write_document};
 pub struct TransactionOperationValue {
   operation: TransactionOperation,
   document: Document,
-  relevant_composite_groups: Vec<CompositeFieldGroup>,
 }
 
 pub enum TransactionOperation {
@@ -25,12 +23,12 @@ pub fn commit_transaction(sql_transaction: &mut Transaction, user_id: &UserId, r
 
   for operation in write_operations {
     match operation.operation {
-      TransactionOperation::Write => write_document(sql_transaction, user_id, operation.document.clone(), &operation.relevant_composite_groups),
+      TransactionOperation::Write => write_document(sql_transaction, user_id, operation.document.clone()),
       TransactionOperation::Delete => {
         let collection_parent_path = operation.document.id.clone().unwrap().collection_parent_path;
         let collection_id = operation.document.id.clone().unwrap().collection_id;
         let document_id = operation.document.id.clone().unwrap().document_id;
-        delete_document(sql_transaction, user_id, &collection_parent_path, &collection_id, &document_id, &operation.relevant_composite_groups)
+        delete_document(sql_transaction, user_id, &collection_parent_path, &collection_id, &document_id)
       }
     }
   }
@@ -1,22 +1,315 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
 use postgres::Transaction;
+use prost::Message;
 use uuid::Uuid;
 
+use crate::protos::document_protos::Document;
+use crate::subscriptions::apply_read_rules_to_changes;
+
+// Resume tokens older than this require a reset. It outlives the subscription TTL so that a client
+// whose subscriptions expired can still resume them.
+pub const CHANGE_HISTORY_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+// The first updates of a new subscription
+#[derive(Debug, Clone)]
+pub struct Subscription {
+  pub subscription_id: String,
+  // Resuming from the token replays the changes the first updates may not include. Every update
+  // carries a resume token, the latest one received can be used to resume later.
+  pub resume_token: i64,
+  // The resume token was older than the change history, or the subscription it came from has been
+  // forgotten, so the first updates are a full reload and the client should discard what it had
+  pub reset_required: bool,
+}
+
+// Where a new subscription picks up from: an earlier subscription of the same client that has
+// ended, and the latest resume token the client received from it
+#[derive(Debug, Clone)]
+pub struct ResumePoint {
+  pub subscription_id: String,
+  pub resume_token: i64,
+}
+
+// How a change affects the documents a subscription matches. A document that stops matching is
+// removed even though it may still exist.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangeType {
+  Added,
+  Modified,
+  Removed,
+}
+
+impl ChangeType {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      ChangeType::Added => "ADDED",
+      ChangeType::Modified => "MODIFIED",
+      ChangeType::Removed => "REMOVED",
+    }
+  }
+
+  pub fn parse(change_type: &str) -> ChangeType {
+    match change_type {
+      "ADDED" => ChangeType::Added,
+      "MODIFIED" => ChangeType::Modified,
+      "REMOVED" => ChangeType::Removed,
+      _ => panic!("Invalid change type {}", change_type),
+    }
+  }
+}
+
+// The positions are only set for subscriptions with ordered results. They are the document's
+// position before and after the change, with the subscription's earlier changes applied.
+#[derive(Debug, Clone)]
+pub struct SubscriptionChange {
+  pub subscription_id: String,
+  pub change_type: ChangeType,
+  pub old_index: Option<i32>,
+  pub new_index: Option<i32>,
+}
+
+impl SubscriptionChange {
+  pub fn added(subscription_id: &str, new_index: Option<i32>) -> SubscriptionChange {
+    SubscriptionChange { subscription_id: subscription_id.to_owned(), change_type: ChangeType::Added, old_index: None, new_index }
+  }
+
+  pub fn modified(subscription_id: &str, old_index: Option<i32>, new_index: Option<i32>) -> SubscriptionChange {
+    SubscriptionChange { subscription_id: subscription_id.to_owned(), change_type: ChangeType::Modified, old_index, new_index }
+  }
+
+  pub fn removed(subscription_id: &str, old_index: Option<i32>) -> SubscriptionChange {
+    SubscriptionChange { subscription_id: subscription_id.to_owned(), change_type: ChangeType::Removed, old_index, new_index: None }
+  }
+}
+
+// Appends the write to the change history and returns the resume token of its updates
+pub fn record_document_change(
+  transaction: &mut Transaction,
+  collection_parent_path: &str,
+  collection_id: &str,
+  document_id: &str,
+  update_id: &str,
+) -> i64 {
+  transaction.execute(
+    "insert into document_changes (collection_parent_path, collection_id, document_id, update_id)
+     values ($1, $2, $3, $4)",
+    &[&collection_parent_path, &collection_id, &document_id, &update_id]).unwrap();
+  current_resume_token(transaction)
+}
+
+// Change sequences are handed out before commit, so a transaction that started earlier can still
+// commit a lower sequence after a higher one was read. A resume token is instead the oldest
+// transaction id still running when the snapshot was taken. Every older transaction committed
+// before the snapshot, so its changes are visible to it and were queued before any update carrying
+// the token. Resuming replays the changes of the token's transaction and of every later one, some
+// of which the client may already have.
+fn current_resume_token(transaction: &mut Transaction) -> i64 {
+  transaction.query_one("select pg_snapshot_xmin(pg_current_snapshot())::TEXT::BIGINT", &[]).unwrap().get(0)
+}
+
+// Unordered subscriptions remember the documents they sent, so that a removal only reaches a client
+// that has the document, and a modification of a document the client doesn't have is sent as an
+// addition. Returns None when the change should not be sent.
+pub fn track_subscription_document(
+  transaction: &mut Transaction,
+  change: SubscriptionChange,
+  collection_parent_path: &str,
+  collection_id: &str,
+  document_id: &str,
+) -> Option<SubscriptionChange> {
+  if change.change_type == ChangeType::Removed {
+    let removed_count = transaction.execute(
+      "delete from subscription_documents
+       where subscription_id = $1 and collection_parent_path = $2 and collection_id = $3 and document_id = $4",
+      &[&change.subscription_id, &collection_parent_path, &collection_id, &document_id]).unwrap();
+    return if removed_count > 0 { Some(change) } else { None };
+  }
+  let added_count = add_subscription_document(transaction, &change.subscription_id, collection_parent_path, collection_id, document_id);
+  if added_count > 0 && change.change_type == ChangeType::Modified {
+    return Some(SubscriptionChange::added(&change.subscription_id, change.new_index));
+  }
+  Some(change)
+}
+
+fn add_subscription_document(
+  transaction: &mut Transaction,
+  subscription_id: &str,
+  collection_parent_path: &str,
+  collection_id: &str,
+  document_id: &str,
+) -> u64 {
+  transaction.execute(
+    "insert into subscription_documents values ($1, $2, $3, $4) ON CONFLICT DO NOTHING",
+    &[&subscription_id, &collection_parent_path, &collection_id, &document_id]).unwrap()
+}
+
+// Forgets the changes older than max_age. Resuming from a token that would replay one of them
+// requires a reset.
+pub fn prune_document_changes(transaction: &mut Transaction, max_age: Duration) {
+  let max_age_seconds = max_age.as_secs_f64();
+  transaction.execute(
+    "with pruned as (
+       delete from document_changes where changed_at < now() - make_interval(secs => $1) returning writer_xid
+     )
+     update document_change_history set pruned_through = greatest(pruned_through, (select max(writer_xid) + 1 from pruned))",
+    &[&max_age_seconds]).unwrap();
+}
+
+// Changes are appended rather than replacing a document's pending change, so that the positions
+// stay consistent when a client applies its changes in queue position order. The initial updates
+// of a subscription share a resume token, so only the queue position orders them. The document
+// data is only kept for changes that leave the document in the subscription's results.
 pub fn write_change_to_update_queues(
   transaction: &mut Transaction,
-  matching_subscriptions: &[String],
+  changes: &[SubscriptionChange],
   collection_parent_path: &str,
   collection_id: &str,
   document_id: &str,
   update_id: &str,
-  document_data: &Option<Vec<u8>>)
+  document_data: &Option<Vec<u8>>,
+  resume_token: i64)
 {
-  for subscription_id in matching_subscriptions {
-    transaction.execute(
-      "delete from update_queues where subscription_id = $1 and collection_parent_path = $2 and collection_id = $3 and document_id = $4",
-      &[&subscription_id, &collection_parent_path, &collection_id, &document_id],
-    ).unwrap();
+  for change in changes {
+    let change_data = if change.change_type == ChangeType::Removed { &None } else { document_data };
     transaction.execute(
-      "insert into update_queues values ($1, $2, $3, $4, $5, $6)",
-      &[&subscription_id, &collection_parent_path, &collection_id, &document_id, &document_data, &update_id]).unwrap();
+      "insert into update_queues
+         (subscription_id, collection_parent_path, collection_id, document_id, document_data, update_id,
+          resume_token, change_type, old_index, new_index)
+       values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+      &[&change.subscription_id, &collection_parent_path, &collection_id, &document_id, change_data, &update_id,
+        &resume_token, &change.change_type.as_str(), &change.old_index, &change.new_index]).unwrap();
   }
 }
+
+// Enqueues the first updates of a new subscription, so that the client starts from the snapshot
+// the subscription was registered in rather than racing a separate read against writes. The
+// documents are everything the subscription currently matches, in order when the subscription is
+// ordered. Resuming an ended subscription only enqueues the documents changed since the token or
+// that it never sent, along with removals for the documents it sent that no longer match, and the
+// new subscription carries on from the documents the ended one sent. The resumed changes pass the
+// same read rules as the changes of a write.
+pub fn write_initial_updates(
+  transaction: &mut Transaction,
+  client_id: &str,
+  subscription_id: &str,
+  resume_point: &Option<ResumePoint>,
+  collection_parent_path: &Option<String>,
+  collection_id: &str,
+  document_id: &Option<String>,
+  documents: &[Document],
+  ordered: bool,
+) -> Subscription {
+  let current_resume_token = current_resume_token(transaction);
+  let index = |i: usize| if ordered { Some(i as i32) } else { None };
+
+  let previous_documents = resume_point.as_ref()
+    .and_then(|resume_point| get_resumable_documents(transaction, client_id, resume_point, collection_id));
+  let (resume_token, previous_documents) = match (resume_point, previous_documents) {
+    (Some(resume_point), Some(previous_documents)) => (resume_point.resume_token, previous_documents),
+    _ => {
+      // The client has every document the subscription matches once it applied the first updates
+      for (i, document) in documents.iter().enumerate() {
+        let id = document.id.clone().unwrap();
+        if !ordered {
+          add_subscription_document(transaction, subscription_id, &id.collection_parent_path, &id.collection_id, &id.document_id);
+        }
+        let change = SubscriptionChange::added(subscription_id, index(i));
+        write_document_to_update_queue(transaction, &change, document, current_resume_token);
+      }
+      return Subscription {
+        subscription_id: subscription_id.to_owned(),
+        resume_token: current_resume_token,
+        reset_required: resume_point.is_some(),
+      };
+    }
+  };
+
+  // A removal only reaches the client when the new subscription knows it sent the document
+  if !ordered {
+    for (previous_collection_parent_path, previous_document_id) in previous_documents.iter() {
+      add_subscription_document(transaction, subscription_id, previous_collection_parent_path, collection_id, previous_document_id);
+    }
+  }
+
+  let changed_documents: HashSet<(String, String)> = transaction.query(
+    "select distinct collection_parent_path, document_id from document_changes
+     where writer_xid >= $1 and collection_id = $2
+       and ($3::TEXT IS NULL or collection_parent_path = $3) and ($4::TEXT IS NULL or document_id = $4)",
+    &[&resume_token, &collection_id, &collection_parent_path, &document_id]).unwrap().into_iter()
+    .map(|row| (row.get(0), row.get(1)))
+    .collect();
+
+  // Removals go first, the positions of the remaining documents don't account for them
+  let matching_documents: HashSet<(String, String)> = documents.iter()
+    .map(|document| document.id.clone().unwrap())
+    .map(|id| (id.collection_parent_path, id.document_id))
+    .collect();
+  for (removed_collection_parent_path, removed_document_id) in previous_documents.difference(&matching_documents) {
+    let changes = apply_read_rules_to_changes(transaction, vec![SubscriptionChange::removed(subscription_id, None)],
+                                              removed_collection_parent_path, collection_id, removed_document_id);
+    let update_id: String = Uuid::new_v4().as_simple().to_string();
+    write_change_to_update_queues(transaction, &changes, removed_collection_parent_path, collection_id,
+                                  removed_document_id, &update_id, &None, current_resume_token);
+  }
+  for (i, document) in documents.iter().enumerate() {
+    let id = document.id.clone().unwrap();
+    let key = (id.collection_parent_path.clone(), id.document_id.clone());
+    let change = if !previous_documents.contains(&key) {
+      SubscriptionChange::added(subscription_id, index(i))
+    } else if changed_documents.contains(&key) {
+      SubscriptionChange::modified(subscription_id, None, index(i))
+    } else {
+      continue;
+    };
+    for change in apply_read_rules_to_changes(transaction, vec![change], &id.collection_parent_path, &id.collection_id, &id.document_id) {
+      write_document_to_update_queue(transaction, &change, document, current_resume_token);
+    }
+  }
+
+  Subscription {
+    subscription_id: subscription_id.to_owned(),
+    resume_token: current_resume_token,
+    reset_required: false,
+  }
+}
+
+// The documents the ended subscription sent that the client may still have. None when they can't
+// be resumed, because the subscription wasn't the client's, has been forgotten, or the changes
+// since the token have been pruned.
+fn get_resumable_documents(
+  transaction: &mut Transaction,
+  client_id: &str,
+  resume_point: &ResumePoint,
+  collection_id: &str,
+) -> Option<HashSet<(String, String)>> {
+  let pruned_through: i64 = transaction.query_one("select pruned_through from document_change_history", &[])
+    .unwrap().get(0);
+  if resume_point.resume_token < pruned_through {
+    return None;
+  }
+  transaction.query_opt(
+    "select 1 from ended_subscriptions where subscription_id = $1 and client_id = $2",
+    &[&resume_point.subscription_id, &client_id]).unwrap()?;
+  Some(transaction.query(
+    "select collection_parent_path, document_id from subscription_documents
+     where subscription_id = $1 and collection_id = $2",
+    &[&resume_point.subscription_id, &collection_id]).unwrap().into_iter()
+    .map(|row| (row.get(0), row.get(1)))
+    .collect())
+}
+
+fn write_document_to_update_queue(
+  transaction: &mut Transaction,
+  change: &SubscriptionChange,
+  document: &Document,
+  resume_token: i64,
+) {
+  let document_id = document.id.clone().unwrap();
+  let mut encoded_document: Vec<u8> = vec![];
+  document.encode(&mut encoded_document).unwrap();
+  write_change_to_update_queues(transaction, &[change.clone()], &document_id.collection_parent_path,
+                                &document_id.collection_id, &document_id.document_id,
+                                &document.update_id.clone().unwrap(), &Some(encoded_document), resume_token);
+}
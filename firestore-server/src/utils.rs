@@ -58,6 +58,23 @@ pub fn field_value_proto_to_sql(field_value: &FieldValue) -> field_value {
   sql_field_value
 }
 
+// Returns a copy of the document containing only the fields named in the field mask. The
+// document id and update id are always retained. A field mask of None returns the full document.
+pub fn apply_field_mask(document: Document, field_mask: &Option<Vec<String>>) -> Document {
+  if let Some(field_mask) = field_mask {
+    let fields: HashMap<String, FieldValue> = document.fields.into_iter()
+      .filter(|(field_name, _)| field_mask.contains(field_name))
+      .collect();
+    Document {
+      id: document.id,
+      fields,
+      update_id: document.update_id,
+    }
+  } else {
+    document
+  }
+}
+
 pub fn null_sql_field_value() -> field_value {
   field_value {
     min: None,
@@ -74,6 +91,239 @@ pub fn null_sql_field_value() -> field_value {
   }
 }
 
+pub const STARTS_WITH_OPERATOR: &str = "starts-with";
+
+// Builds the sql constraint comparing a field_value column with a query value. The starts-with
+// operator is rewritten into a range so that the btree index on the column can be used.
+pub fn field_value_constraint(
+  column_name: &str,
+  operator: &str,
+  arg_count: usize,
+  value: &field_value)
+  -> (String, Vec<field_value>)
+{
+  if operator == STARTS_WITH_OPERATOR {
+    let (lower_bound, upper_bound) = prefix_range_bounds(value);
+    return (format!("({0} >= ${1} and {0} < ${2})", column_name, arg_count, arg_count + 1),
+            vec![lower_bound, upper_bound]);
+  }
+
+  if operator == EXISTS_OPERATOR {
+    // The field has a value of some type, which every row for the field satisfies
+    return ("TRUE".to_owned(), vec![]);
+  }
+  if operator == NOT_EXISTS_OPERATOR {
+    panic!("The {} operator cannot be applied to a field value column", NOT_EXISTS_OPERATOR);
+  }
+  if let Some(type_name) = parse_is_type_operator(operator) {
+    return field_value_type_constraint(column_name, type_name, arg_count);
+  }
+
+  let (comparison, cross_type) = parse_range_operator(operator);
+  // The comparison is spliced into the sql, so it must be checked against the known operators
+  assert!(COMPARISON_OPERATORS.contains(&comparison), "Invalid query operator provided: {}", operator);
+  if cross_type {
+    return no_op_field_value_constraint(column_name, comparison, arg_count, value);
+  }
+
+  // Bound inequalities to the type of the query value, so eg. age > 25 does not return string ages
+  let ((lower_bound, lower_operator), (upper_bound, upper_operator)) = type_range_bounds(value);
+  match comparison {
+    ">" | ">=" => (format!("({0} {1} ${2} and {0} {3} ${4})", column_name, comparison, arg_count, upper_operator, arg_count + 1),
+                   vec![value.clone(), upper_bound]),
+    "<" | "<=" => (format!("({0} {1} ${2} and {0} {3} ${4})", column_name, comparison, arg_count, lower_operator, arg_count + 1),
+                   vec![value.clone(), lower_bound]),
+    _ => no_op_field_value_constraint(column_name, comparison, arg_count, value),
+  }
+}
+
+pub const COMPARISON_OPERATORS: [&str; 6] = ["<", "<=", "=", "!=", ">", ">="];
+pub const EXISTS_OPERATOR: &str = "exists";
+pub const NOT_EXISTS_OPERATOR: &str = "not-exists";
+
+// The type names accepted by the is-type operator, eg. "is-type(double)". The number type
+// matches both integers and doubles.
+pub const FIELD_VALUE_TYPE_NAMES: [&str; 9] = ["null", "boolean", "integer", "double", "number", "timestamp", "string", "bytes", "reference"];
+
+pub fn is_type_operator(type_name: &str) -> String {
+  format!("is-type({})", type_name)
+}
+
+pub fn parse_is_type_operator(operator: &str) -> Option<&str> {
+  let type_name = operator.strip_prefix("is-type(")?.strip_suffix(')')?;
+  if !FIELD_VALUE_TYPE_NAMES.contains(&type_name) {
+    panic!("Invalid type provided to the is-type operator: {}", type_name);
+  }
+  Some(type_name)
+}
+
+// Returns the names of every type the value is an instance of
+pub fn field_value_type_names(value: &field_value) -> Vec<&'static str> {
+  if value.null_value.is_some() {
+    vec!["null"]
+  } else if value.boolean_value.is_some() {
+    vec!["boolean"]
+  } else if value.integer_value.is_some() {
+    vec!["integer", "number"]
+  } else if value.double_value.is_some() {
+    vec!["double", "number"]
+  } else if value.timestamp_nanos.is_some() && value.timestamp_seconds.is_some() {
+    vec!["timestamp"]
+  } else if value.string_value.is_some() {
+    vec!["string"]
+  } else if value.bytes_value.is_some() {
+    vec!["bytes"]
+  } else if value.reference_value.is_some() {
+    vec!["reference"]
+  } else {
+    vec![]
+  }
+}
+
+// Constrains the column to the range of values of the type so the btree index can be used.
+// Integers and doubles share a range, so they are told apart by which member is set.
+fn field_value_type_constraint(column_name: &str, type_name: &str, arg_count: usize) -> (String, Vec<field_value>) {
+  let mut type_value = field_value::default();
+  match type_name {
+    "null" => type_value.null_value = Some(Unit::Exists),
+    "boolean" => type_value.boolean_value = Some(false),
+    "integer" | "double" | "number" => type_value.integer_value = Some(0),
+    "timestamp" => {
+      type_value.timestamp_seconds = Some(0);
+      type_value.timestamp_nanos = Some(0);
+    }
+    "string" => type_value.string_value = Some("".to_owned()),
+    "bytes" => type_value.bytes_value = Some(vec![]),
+    "reference" => type_value.reference_value = Some("".to_owned()),
+    _ => panic!("Invalid type provided to the is-type operator: {}", type_name),
+  }
+
+  let ((lower_bound, lower_operator), (upper_bound, upper_operator)) = type_range_bounds(&type_value);
+  let mut constraint = format!("({0} {1} ${2} and {0} {3} ${4}", column_name, lower_operator, arg_count, upper_operator, arg_count + 1);
+  match type_name {
+    "integer" => constraint.push_str(&format!(" and ({}).integer_value IS NOT NULL", column_name)),
+    "double" => constraint.push_str(&format!(" and ({}).double_value IS NOT NULL", column_name)),
+    _ => {}
+  }
+  constraint.push(')');
+  (constraint, vec![lower_bound, upper_bound])
+}
+
+// Inequalities only match values of the same type as the query value unless the operator has
+// this prefix, eg. "cross-type:>" matches every value ordered after the query value
+pub const CROSS_TYPE_OPERATOR_PREFIX: &str = "cross-type:";
+
+// Splits an operator into its comparison and whether the comparison may span value types
+pub fn parse_range_operator(operator: &str) -> (&str, bool) {
+  if let Some(comparison) = operator.strip_prefix(CROSS_TYPE_OPERATOR_PREFIX) {
+    (comparison, true)
+  } else {
+    (operator, false)
+  }
+}
+
+// Returns the lower and upper bounds of the values with the same type as the given value, each
+// paired with the operator a value in the range must satisfy against the bound. Where the end of
+// a type cannot be represented, the upper bound is the first value of the next type.
+pub fn type_range_bounds(value: &field_value) -> ((field_value, &'static str), (field_value, &'static str)) {
+  let mut lower_bound = field_value::default();
+  let mut upper_bound = field_value::default();
+  let mut upper_operator = "<=";
+  if value.min.is_some() {
+    lower_bound.min = Some(Unit::Exists);
+    upper_bound.min = Some(Unit::Exists);
+  } else if value.null_value.is_some() {
+    lower_bound.null_value = Some(Unit::Exists);
+    upper_bound.null_value = Some(Unit::Exists);
+  } else if value.boolean_value.is_some() {
+    lower_bound.boolean_value = Some(false);
+    upper_bound.boolean_value = Some(true);
+  } else if value.integer_value.is_some() || value.double_value.is_some() {
+    // postgres orders NaN after every other number
+    lower_bound.double_value = Some(f64::NEG_INFINITY);
+    upper_bound.double_value = Some(f64::NAN);
+  } else if value.timestamp_nanos.is_some() && value.timestamp_seconds.is_some() {
+    lower_bound.timestamp_seconds = Some(i64::MIN);
+    lower_bound.timestamp_nanos = Some(i64::MIN);
+    upper_bound.timestamp_seconds = Some(i64::MAX);
+    upper_bound.timestamp_nanos = Some(i64::MAX);
+  } else if value.string_value.is_some() {
+    lower_bound.string_value = Some("".to_owned());
+    upper_bound.bytes_value = Some(vec![]);
+    upper_operator = "<";
+  } else if value.bytes_value.is_some() {
+    lower_bound.bytes_value = Some(vec![]);
+    upper_bound.reference_value = Some("".to_owned());
+    upper_operator = "<";
+  } else if value.reference_value.is_some() {
+    lower_bound.reference_value = Some("".to_owned());
+    upper_bound.max = Some(Unit::Exists);
+    upper_operator = "<";
+  } else {
+    lower_bound.max = Some(Unit::Exists);
+    upper_bound.max = Some(Unit::Exists);
+  }
+  ((lower_bound, ">="), (upper_bound, upper_operator))
+}
+
+// Returns the half open range [lower, upper) containing every string or bytes value that starts
+// with the prefix. The upper bound relies on text sorting by code point, which string_cmp in
+// create_composite_type.sql enforces with the C collation.
+pub fn prefix_range_bounds(prefix: &field_value) -> (field_value, field_value) {
+  assert!(prefix.string_value.is_some() || prefix.bytes_value.is_some(),
+          "The {} operator requires a string or bytes value", STARTS_WITH_OPERATOR);
+  let mut upper_bound = field_value::default();
+  if let Some(string_prefix) = &prefix.string_value {
+    let mut chars: Vec<char> = string_prefix.chars().collect();
+    while let Some(last_char) = chars.pop() {
+      if let Some(next_char) = next_char(last_char) {
+        chars.push(next_char);
+        upper_bound.string_value = Some(chars.into_iter().collect());
+        return (prefix.clone(), upper_bound);
+      }
+    }
+    // Every string starts with the prefix, so the range ends at the first bytes value
+    upper_bound.bytes_value = Some(vec![]);
+  } else if let Some(bytes_prefix) = &prefix.bytes_value {
+    let mut bytes = bytes_prefix.clone();
+    while let Some(last_byte) = bytes.pop() {
+      if last_byte < u8::MAX {
+        bytes.push(last_byte + 1);
+        upper_bound.bytes_value = Some(bytes);
+        return (prefix.clone(), upper_bound);
+      }
+    }
+    // Every bytes value starts with the prefix, so the range ends at the first reference value
+    upper_bound.reference_value = Some("".to_owned());
+  }
+  (prefix.clone(), upper_bound)
+}
+
+fn next_char(c: char) -> Option<char> {
+  let mut code_point = c as u32 + 1;
+  // skip the surrogate range, which contains no valid chars
+  if code_point == 0xD800 {
+    code_point = 0xE000;
+  }
+  char::from_u32(code_point)
+}
+
+// Returns the constraint matching the starts-with subscriptions in prefix_column whose stored
+// prefix the value starts with, and the constraint's argument. Only string and bytes values have
+// prefixes. The subscription's value is compared directly, rather than enumerating the prefixes of
+// the value, so the cost doesn't grow with the square of the value's length.
+pub fn stored_prefix_constraint(prefix_column: &str, arg_count: usize, value: &field_value) -> Option<(String, Box<dyn ToSql + Sync>)> {
+  if let Some(string_value) = &value.string_value {
+    Some((format!("starts_with(${0}::TEXT, ({1}).string_value)", arg_count, prefix_column),
+          Box::new(string_value.clone())))
+  } else if let Some(bytes_value) = &value.bytes_value {
+    Some((format!("substring(${0}::BYTEA from 1 for length(({1}).bytes_value)) = ({1}).bytes_value", arg_count, prefix_column),
+          Box::new(bytes_value.clone())))
+  } else {
+    None
+  }
+}
+
 //TODO: Fix this to avoid information loss
 pub fn prepare_field_value_constraint(
   column_name: &str,
@@ -165,4 +415,15 @@ fn prepare_numeric_field_value_constraint(
     constraint = format!("({0} != ${1} and {0} != ${2})", column_name, arg_count, arg_count + 1);
   }
   return (constraint, vec![double_return_value, integer_return_value]);
-}
\ No newline at end of file
+}
+// Postgres truncates identifiers longer than this many bytes, so two long field names could
+// silently end up naming the same column
+pub const MAX_IDENTIFIER_LENGTH: usize = 63;
+
+// Quotes a table or column name so that it can be spliced into sql. Field names are user provided,
+// so they can contain anything, including quotes.
+pub fn quote_identifier(identifier: &str) -> String {
+  assert!(!identifier.is_empty() && !identifier.contains('\0'), "Invalid identifier {:?}", identifier);
+  assert!(identifier.len() <= MAX_IDENTIFIER_LENGTH, "The identifier {} is longer than {} bytes", identifier, MAX_IDENTIFIER_LENGTH);
+  format!("\"{}\"", identifier.replace('"', "\"\""))
+}
@@ -11,7 +11,9 @@ use prost::Message;
 use uuid::Uuid;
 
 use crate::basic_read::{get_document, get_matching_basic_subscription_ids};
-use crate::composite_query::{add_document_to_composite_query_tables, CompositeFieldGroup, delete_document_from_composite_query_tables, get_matching_composite_query_subscriptions};
+use crate::composite_groups::get_composite_groups_for_collection;
+use crate::composite_query::{add_document_to_composite_query_tables, add_document_to_composite_subscription_results, delete_document_from_composite_query_tables, delete_document_from_composite_subscription_results};
+use crate::full_text_search::{add_document_to_full_text_search_table, delete_document_from_full_text_search_table};
 use crate::protos::document_protos::Document;
 use crate::protos::document_protos::field_value::Value;
 use crate::protos::document_protos::FieldValue;
@@ -19,31 +21,55 @@ use crate::security_rules::{Operation, operation_is_allowed, UserId};
 use crate::security_rules::UserId::User;
 use crate::simple_query::{add_document_to_simple_query_table, delete_document_from_simple_query_table, get_matching_simple_query_subscriptions};
 use crate::sql_types::field_value;
-use crate::update_queue::write_change_to_update_queues;
+use crate::subscriptions::apply_read_rules_to_changes;
+use crate::update_queue::{record_document_change, SubscriptionChange, write_change_to_update_queues};
 
+// Returns the subscriptions that match the new document, with its position in their results when
+// they are ordered
 fn create_document(
   transaction: &mut Transaction,
   collection_parent_path: &str,
   collection_id: &str,
   document_id: &str,
   update_id: &str,
+  encoded_document: &[u8],
   document: &Document,
-  composite_groups: &[CompositeFieldGroup],
-) {
-  let mut encoded_document: Vec<u8> = vec![];
-  document.encode(&mut encoded_document).unwrap();
+) -> Vec<(String, Option<i32>)> {
+  let composite_groups = get_composite_groups_for_collection(transaction, collection_parent_path, collection_id);
 
-  add_document_to_documents_table(transaction, collection_parent_path, collection_id, document_id, update_id, &encoded_document);
+  add_document_to_documents_table(transaction, collection_parent_path, collection_id, document_id, update_id, encoded_document);
   add_document_to_simple_query_table(transaction, collection_parent_path, collection_id, document_id, document);
-  add_document_to_composite_query_tables(transaction, collection_parent_path, collection_id, document_id, document, composite_groups);
+  add_document_to_composite_query_tables(transaction, collection_parent_path, collection_id, document_id, document, &composite_groups);
+  add_document_to_full_text_search_table(transaction, collection_parent_path, collection_id, document_id, document);
 
   let mut matching_subscriptions = vec![];
-  matching_subscriptions.extend(get_matching_basic_subscription_ids(transaction, collection_parent_path, collection_id, document_id).into_iter());
-  matching_subscriptions.extend(get_matching_simple_query_subscriptions(transaction, collection_parent_path, collection_id, document).into_iter());
-  matching_subscriptions.extend(get_matching_composite_query_subscriptions(transaction, document, composite_groups).into_iter());
+  matching_subscriptions.extend(get_matching_basic_subscription_ids(transaction, collection_parent_path, collection_id, document_id).into_iter().map(|x| (x, None)));
+  matching_subscriptions.extend(get_matching_simple_query_subscriptions(transaction, collection_parent_path, collection_id, document).into_iter().map(|x| (x, None)));
+  matching_subscriptions.extend(add_document_to_composite_subscription_results(transaction, document, &composite_groups).into_iter().map(|(x, position)| (x, Some(position))));
+  matching_subscriptions
+}
 
-  write_change_to_update_queues(transaction, &matching_subscriptions, collection_parent_path, collection_id, document_id, update_id, &Some(encoded_document));
-  // Todo: Ping client-server connection to trigger update (this would actually happen after the transaction)
+// Returns the subscriptions that matched the old document, with its position in their results
+// when they are ordered, or None if the document doesn't exist
+fn remove_document(
+  transaction: &mut Transaction,
+  user_id: &UserId,
+  collection_parent_path: &str,
+  collection_id: &str,
+  document_id: &str,
+) -> Option<Vec<(String, Option<i32>)>> {
+  let document = get_document(transaction, user_id, collection_parent_path, collection_id, document_id, &None)?;
+  let composite_groups = get_composite_groups_for_collection(transaction, collection_parent_path, collection_id);
+  delete_document_from_documents_table(transaction, collection_parent_path, collection_id, document_id);
+  delete_document_from_simple_query_table(transaction, collection_parent_path, collection_id, document_id);
+  delete_document_from_composite_query_tables(transaction, collection_parent_path, collection_id, document_id, &composite_groups);
+  delete_document_from_full_text_search_table(transaction, collection_parent_path, collection_id, document_id);
+
+  let mut matching_subscriptions = vec![];
+  matching_subscriptions.extend(get_matching_basic_subscription_ids(transaction, collection_parent_path, collection_id, document_id).into_iter().map(|x| (x, None)));
+  matching_subscriptions.extend(get_matching_simple_query_subscriptions(transaction, collection_parent_path, collection_id, &document).into_iter().map(|x| (x, None)));
+  matching_subscriptions.extend(delete_document_from_composite_subscription_results(transaction, collection_parent_path, collection_id, document_id).into_iter().map(|(x, position)| (x, Some(position))));
+  Some(matching_subscriptions)
 }
 
 pub fn delete_document(
@@ -52,7 +78,6 @@ pub fn delete_document(
   collection_parent_path: &str,
   collection_id: &str,
   document_id: &str,
-  composite_groups: &[CompositeFieldGroup],
 ) {
   if let User(user_id) = user_id {
     assert!(operation_is_allowed(user_id, &Operation::Delete,
@@ -60,18 +85,15 @@ pub fn delete_document(
                                  collection_id, &Some(document_id.to_owned())));
   }
 
-  if let Some(document) = get_document(transaction, user_id, collection_parent_path, collection_id, document_id) {
-    delete_document_from_documents_table(transaction, collection_parent_path, collection_id, document_id);
-    delete_document_from_simple_query_table(transaction, collection_parent_path, collection_id, document_id);
-    delete_document_from_composite_query_tables(transaction, collection_parent_path, collection_id, document_id, composite_groups);
-
-    let mut matching_subscriptions = vec![];
-    matching_subscriptions.extend(get_matching_basic_subscription_ids(transaction, collection_parent_path, collection_id, document_id).into_iter());
-    matching_subscriptions.extend(get_matching_simple_query_subscriptions(transaction, collection_parent_path, collection_id, &document).into_iter());
-    matching_subscriptions.extend(get_matching_composite_query_subscriptions(transaction, &document, composite_groups).into_iter());
+  if let Some(old_subscriptions) = remove_document(transaction, user_id, collection_parent_path, collection_id, document_id) {
+    let changes: Vec<SubscriptionChange> = old_subscriptions.iter()
+      .map(|(subscription_id, old_index)| SubscriptionChange::removed(subscription_id, *old_index))
+      .collect();
+    let changes = apply_read_rules_to_changes(transaction, changes, collection_parent_path, collection_id, document_id);
 
     let update_id: String = Uuid::new_v4().as_simple().to_string();
-    write_change_to_update_queues(transaction, &matching_subscriptions, collection_parent_path, collection_id, document_id, &update_id, &None);
+    let resume_token = record_document_change(transaction, collection_parent_path, collection_id, document_id, &update_id);
+    write_change_to_update_queues(transaction, &changes, collection_parent_path, collection_id, document_id, &update_id, &None, resume_token);
     // Todo: Ping client-server connection to trigger update (this would actually happen after the transaction)
   }
 }
@@ -80,7 +102,6 @@ pub fn write_document(
   transaction: &mut Transaction,
   user_id: &UserId,
   mut document: Document,
-  composite_groups: &[CompositeFieldGroup],
 )
 {
   let collection_parent_path: String = document.id.clone().unwrap().collection_parent_path.clone();
@@ -102,8 +123,29 @@ pub fn write_document(
                                  &collection_id, &Some(document_id.to_owned())));
   }
 
-  delete_document(transaction, &UserId::Admin, &collection_parent_path, &collection_id, &document_id, composite_groups);
-  create_document(transaction, &collection_parent_path, &collection_id, &document_id, &update_id, &document, composite_groups);
+  let mut encoded_document: Vec<u8> = vec![];
+  document.encode(&mut encoded_document).unwrap();
+  let old_subscriptions: HashMap<String, Option<i32>> = remove_document(transaction, &UserId::Admin, &collection_parent_path, &collection_id, &document_id)
+    .unwrap_or_default().into_iter().collect();
+  let new_subscriptions = create_document(transaction, &collection_parent_path, &collection_id, &document_id, &update_id, &encoded_document, &document);
+
+  // A subscription that matched both versions sees a single modification
+  let mut changes: Vec<SubscriptionChange> = old_subscriptions.iter()
+    .filter(|(subscription_id, _)| !new_subscriptions.iter().any(|(x, _)| x == *subscription_id))
+    .map(|(subscription_id, old_index)| SubscriptionChange::removed(subscription_id, *old_index))
+    .collect();
+  for (subscription_id, new_index) in &new_subscriptions {
+    match old_subscriptions.get(subscription_id) {
+      Some(old_index) => changes.push(SubscriptionChange::modified(subscription_id, *old_index, *new_index)),
+      None => changes.push(SubscriptionChange::added(subscription_id, *new_index)),
+    }
+  }
+
+  let changes = apply_read_rules_to_changes(transaction, changes, &collection_parent_path, &collection_id, &document_id);
+
+  let resume_token = record_document_change(transaction, &collection_parent_path, &collection_id, &document_id, &update_id);
+  write_change_to_update_queues(transaction, &changes, &collection_parent_path, &collection_id, &document_id, &update_id, &Some(encoded_document), resume_token);
+  // Todo: Ping client-server connection to trigger update (this would actually happen after the transaction)
 }
 
 fn add_document_to_documents_table(
@@ -54,8 +54,8 @@ $$language plpgsql;
 
 create or replace function integer_float_cmp(a int8, b float8) returns int2 as $$
 begin
-  if a > b then
-    return 1;
+  if a < b then
+    return -1;
   elsif a > b then
     return 1;
   else
@@ -64,11 +64,13 @@ begin
 end;
 $$language plpgsql;
 
+-- Strings sort by code point regardless of the database collation, like Firestore, which the
+-- upper bounds of starts-with ranges rely on
 create or replace function string_cmp(a text, b text) returns int2 as $$
 begin
-  if a < b then
+  if a COLLATE "C" < b COLLATE "C" then
     return -1;
-  elsif a > b then
+  elsif a COLLATE "C" > b COLLATE "C" then
     return 1;
   else
     return 0;
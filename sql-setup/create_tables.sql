@@ -15,7 +15,7 @@ CREATE TABLE basic_subscriptions (
   collection_parent_path      TEXT,
   collection_id               TEXT,
   document_id                 TEXT,
-  subscription_id             TEXT
+  subscription_id             TEXT,
   PRIMARY KEY (subscription_id)
 );
 
@@ -34,6 +34,16 @@ CREATE TABLE simple_query_lookup (
 CREATE INDEX simple_query_idx ON simple_query_lookup(collection_id, field_name, field_value, collection_parent_path);
 CREATE INDEX simple_query_deletion_idx ON simple_query_lookup(collection_parent_path, collection_id, document_id);
 
+CREATE TABLE simple_index_rules (
+  collection_parent_path      TEXT,
+  collection_id               TEXT,
+  field_name                  TEXT,
+  is_indexed                  BOOLEAN
+);
+
+CREATE UNIQUE INDEX simple_index_rules_idx
+ON simple_index_rules(collection_id, field_name, collection_parent_path) NULLS NOT DISTINCT;
+
 CREATE TABLE simple_query_subscriptions (
   collection_parent_path      TEXT,
   collection_id               TEXT,
@@ -49,20 +59,160 @@ simple_query_subscriptions(collection_id, field_name, field_operator, field_valu
 
 CREATE TABLE client_subscriptions (
   subscription_id     TEXT,
-  client_id           TEXT
+  client_id           TEXT,
+  composite_group_id  TEXT,
+  ttl_seconds         BIGINT,
+  subscribed_at       TIMESTAMPTZ DEFAULT now(),
+  user_id             TEXT,
+  is_admin            BOOLEAN DEFAULT false,
+  collection_parent_path TEXT,
+  collection_id       TEXT,
+  document_id         TEXT
 );
 
 CREATE INDEX client_subscriptions_subscription_id_idx ON client_subscriptions(subscription_id);
 CREATE INDEX client_subscriptions_client_id_idx ON client_subscriptions(client_id);
 
+CREATE TABLE composite_groups (
+  group_id                          TEXT,
+  collection_parent_path            TEXT,
+  collection_id                     TEXT,
+  primary_field_name                TEXT,
+  sorted_secondary_field_names      TEXT[],
+  descending_field_names            TEXT[],
+  state                             TEXT,
+  backfill_collection_parent_path   TEXT,
+  backfill_document_id              TEXT,
+  replaces_group_id                 TEXT,
+  PRIMARY KEY (group_id)
+);
+
+CREATE INDEX composite_groups_collection_idx ON composite_groups(collection_id, collection_parent_path);
+
+CREATE TABLE client_ping_times (
+  client_id           TEXT,
+  ping_time           TIMESTAMPTZ,
+  ttl_seconds         BIGINT,
+  PRIMARY KEY (client_id)
+);
+
+CREATE TABLE subscription_cancellations (
+  subscription_id     TEXT,
+  client_id           TEXT,
+  reason              TEXT,
+  cancelled_at        TIMESTAMPTZ DEFAULT now()
+);
+
+CREATE INDEX subscription_cancellations_client_id_idx ON subscription_cancellations(client_id);
+CREATE INDEX subscription_cancellations_cancelled_at_idx ON subscription_cancellations(cancelled_at);
+CREATE INDEX client_ping_times_ping_time_idx ON client_ping_times(ping_time);
+
+CREATE TABLE document_changes (
+  change_sequence             BIGSERIAL,
+  collection_parent_path      TEXT,
+  collection_id               TEXT,
+  document_id                 TEXT,
+  update_id                   TEXT,
+  changed_at                  TIMESTAMPTZ DEFAULT now(),
+  writer_xid                  BIGINT DEFAULT pg_current_xact_id()::TEXT::BIGINT,
+  PRIMARY KEY (change_sequence)
+);
+
+CREATE INDEX document_changes_collection_idx ON document_changes(collection_id, writer_xid);
+CREATE INDEX document_changes_changed_at_idx ON document_changes(changed_at);
+
+CREATE TABLE document_change_history (
+  pruned_through              BIGINT
+);
+
+INSERT INTO document_change_history VALUES (0);
+
+CREATE TABLE composite_subscription_results (
+  subscription_id             TEXT,
+  collection_parent_path      TEXT,
+  collection_id               TEXT,
+  document_id                 TEXT,
+  primary_value               field_value,
+  descending                  BOOLEAN,
+  PRIMARY KEY (subscription_id, collection_parent_path, collection_id, document_id)
+);
+
+CREATE INDEX composite_subscription_results_document_idx
+ON composite_subscription_results(collection_parent_path, collection_id, document_id);
+
+CREATE INDEX composite_subscription_results_order_idx
+ON composite_subscription_results(subscription_id, primary_value, collection_parent_path, collection_id, document_id);
+
+-- The documents basic and simple query subscriptions have sent to their client. Composite
+-- subscriptions keep theirs in composite_subscription_results.
+CREATE TABLE subscription_documents (
+  subscription_id             TEXT,
+  collection_parent_path      TEXT,
+  collection_id               TEXT,
+  document_id                 TEXT,
+  PRIMARY KEY (subscription_id, collection_parent_path, collection_id, document_id)
+);
+
+-- Subscriptions that ended recently. Their rows in subscription_documents are kept, so that a
+-- client resuming one only receives removals for documents it was sent.
+CREATE TABLE ended_subscriptions (
+  subscription_id             TEXT,
+  client_id                   TEXT,
+  ended_at                    TIMESTAMPTZ DEFAULT now(),
+  PRIMARY KEY (subscription_id)
+);
+
+CREATE INDEX ended_subscriptions_ended_at_idx ON ended_subscriptions(ended_at);
+
 CREATE TABLE update_queues (
+  queue_position              BIGSERIAL,
   subscription_id             TEXT,
   collection_parent_path      TEXT,
   collection_id               TEXT,
   document_id                 TEXT,
   document_data               BYTEA,
-  update_id                   TEXT
+  update_id                   TEXT,
+  resume_token                BIGINT,
+  change_type                 TEXT,
+  old_index                   INTEGER,
+  new_index                   INTEGER
 );
 
 CREATE INDEX update_queues_subscription_id_idx ON update_queues(subscription_id);
 CREATE INDEX update_queues_update_id_idx ON update_queues(update_id);
+
+CREATE TABLE full_text_search_fields (
+  collection_parent_path      TEXT,
+  collection_id               TEXT,
+  field_name                  TEXT
+);
+
+CREATE UNIQUE INDEX full_text_search_fields_idx
+ON full_text_search_fields(collection_id, field_name, collection_parent_path) NULLS NOT DISTINCT;
+
+CREATE TABLE full_text_search_lookup (
+  collection_parent_path      TEXT,
+  collection_id               TEXT,
+  document_id                 TEXT,
+  field_name                  TEXT,
+  search_vector               TSVECTOR,
+  PRIMARY KEY (collection_parent_path, collection_id, document_id, field_name)
+);
+
+CREATE INDEX full_text_search_idx ON full_text_search_lookup USING GIN (search_vector);
+
+CREATE TABLE query_stats (
+  collection_parent_path      TEXT,
+  collection_id               TEXT,
+  field_names                 TEXT[],
+  operators                   TEXT[],
+  is_subscription             BOOLEAN,
+  needs_composite_group       BOOLEAN,
+  composite_group_id          TEXT,
+  failed                      BOOLEAN,
+  duration_ms                 DOUBLE PRECISION,
+  recorded_at                 TIMESTAMPTZ
+);
+
+CREATE INDEX query_stats_shape_idx ON query_stats(collection_id, field_names, operators, collection_parent_path);
+CREATE INDEX query_stats_composite_group_id_idx ON query_stats(composite_group_id);